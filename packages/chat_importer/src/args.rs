@@ -1,9 +1,14 @@
+use chrono::{Local, NaiveDate, NaiveTime, TimeZone};
+use ibackuptool2::Backup;
 use lazy_static::*;
 use log::Level;
 use path_absolutize::Absolutize;
 use path_ext::PathExt;
+use serde::Deserialize;
+use std::collections::BTreeMap;
 use std::io::{Error, ErrorKind};
-use std::path::PathBuf;
+use std::iter::once;
+use std::path::{Path, PathBuf};
 use structopt::StructOpt;
 use walkdir::WalkDir;
 
@@ -29,39 +34,491 @@ impl Verbosity {
 pub enum SubCommand {
     #[structopt(name = "qq", about = "import qq mht files")]
     QQ {
-        #[structopt(short = "o", default_value = "DarkSky")]
+        #[structopt(short = "o", long = "owner", default_value = "DarkSky")]
         owner: String,
         #[structopt(name = "DIR", parse(try_from_str = check_path))]
         path: Vec<PathBuf>,
     },
     #[structopt(name = "wc", about = "import wechat from ios backup")]
     WeChat {
-        #[structopt(short = "c")]
+        #[structopt(name = "chats", short = "c", long = "chats")]
         chat_names: Option<String>,
+        #[structopt(
+            short = "a",
+            long = "accounts",
+            help = "accounts to import, comma separated wxid, nickname or hash"
+        )]
+        accounts: Option<String>,
+        #[structopt(
+            long = "list",
+            help = "list chats with message counts instead of importing"
+        )]
+        list: bool,
+        #[structopt(
+            name = "match",
+            long = "match",
+            help = "select chats whose hash, wxid or remark match regex"
+        )]
+        pattern: Option<String>,
+        #[structopt(
+            long = "groups",
+            conflicts_with = "private",
+            help = "select group chats only"
+        )]
+        groups: bool,
+        #[structopt(long = "private", help = "select private chats only")]
+        private: bool,
+        #[structopt(
+            long = "active-since",
+            parse(try_from_str = parse_date),
+            help = "select chats with messages since date, e.g. 2020-01-31"
+        )]
+        active_since: Option<NaiveDate>,
+        #[structopt(
+            long = "active-until",
+            parse(try_from_str = parse_date),
+            help = "select chats with messages until date"
+        )]
+        active_until: Option<NaiveDate>,
+        #[structopt(
+            long = "min-messages",
+            help = "select chats with at least this many messages"
+        )]
+        min_messages: Option<i64>,
+        #[structopt(
+            long = "include",
+            help = "file of chats to select, one hash/wxid/remark per line"
+        )]
+        include: Option<PathBuf>,
+        #[structopt(
+            long = "exclude",
+            help = "file of chats to skip, one hash/wxid/remark per line"
+        )]
+        exclude: Option<PathBuf>,
+        #[structopt(
+            long = "snapshots",
+            help = "import backups of the same device oldest first, tracking deleted messages"
+        )]
+        snapshots: bool,
         #[structopt(name = "DIR", parse(try_from_str = check_path))]
         path: Vec<PathBuf>,
     },
     #[structopt(name = "sms", about = "import sms from ios backup")]
     SMS {
-        #[structopt(short = "o", default_value = "DarkSky")]
+        #[structopt(short = "o", long = "owner", default_value = "DarkSky")]
         owner: String,
+        #[structopt(
+            long = "snapshots",
+            help = "import backups of the same device oldest first, tracking deleted messages"
+        )]
+        snapshots: bool,
         #[structopt(name = "DIR", parse(try_from_str = check_path))]
         path: Vec<PathBuf>,
     },
+    #[structopt(name = "slack", about = "import slack workspace export folder or zip")]
+    Slack {
+        #[structopt(short = "w", long = "workspace")]
+        workspace: Option<String>,
+        #[structopt(name = "PATH", parse(try_from_str = check_path))]
+        path: Vec<PathBuf>,
+    },
+    #[structopt(name = "mail", about = "import mbox files or eml folders")]
+    Mail {
+        #[structopt(short = "o", long = "owner", default_value = "DarkSky")]
+        owner: String,
+        #[structopt(name = "PATH", parse(try_from_str = check_path))]
+        path: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "text",
+        about = "import plain text chat logs, built-in profiles: line, kakao, kakao-ko, signal"
+    )]
+    TextLog {
+        #[structopt(
+            short = "p",
+            long = "profile",
+            help = "built-in profile name or toml/json profile path"
+        )]
+        profile: String,
+        #[structopt(short = "o", long = "owner", default_value = "DarkSky")]
+        owner: String,
+        #[structopt(
+            short = "c",
+            long = "chat-id",
+            help = "override chat id, defaults to file name"
+        )]
+        chat_id: Option<String>,
+        #[structopt(
+            short = "z",
+            long = "timezone",
+            help = "override timezone, e.g. +09:00"
+        )]
+        timezone: Option<String>,
+        #[structopt(name = "PATH", parse(try_from_str = check_path))]
+        path: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "auto",
+        about = "detect the kind of every input under the paths and import it"
+    )]
+    Auto {
+        #[structopt(short = "o", long = "owner", default_value = "DarkSky")]
+        owner: String,
+        #[structopt(name = "PATH", parse(try_from_str = check_path))]
+        path: Vec<PathBuf>,
+    },
+    #[structopt(
+        name = "inspect",
+        about = "show device, accounts and chats in ios backups"
+    )]
+    Inspect {
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "text",
+            possible_values = &["text", "json"])]
+        format: String,
+        #[structopt(name = "DIR", parse(try_from_str = check_path))]
+        path: Vec<PathBuf>,
+    },
+    #[structopt(name = "export", about = "export chats of the database")]
+    Export {
+        #[structopt(possible_values = &["html", "txt", "md", "json", "pdf"])]
+        format: String,
+        #[structopt(long = "chat-type", help = "e.g. WeChat, SMS, QQ")]
+        chat_type: Option<String>,
+        #[structopt(long = "owner-id")]
+        owner_id: Option<String>,
+        #[structopt(long = "group-id", help = "the chat, e.g. a wxid or phone number")]
+        group_id: Option<String>,
+        #[structopt(
+            short = "O",
+            long = "out",
+            default_value = "export",
+            help = "folder to write a folder per chat into"
+        )]
+        out: PathBuf,
+        #[structopt(
+            long = "page-size",
+            default_value = "500",
+            help = "messages per html page"
+        )]
+        page_size: usize,
+        #[structopt(
+            long = "font",
+            help = "ttf font to embed in pdfs, needed for text beyond latin scripts"
+        )]
+        font: Option<PathBuf>,
+    },
+    #[structopt(
+        name = "search",
+        about = "search the messages of the database, --since and --until limit the dates"
+    )]
+    Search {
+        #[structopt(
+            help = r#"words and "quoted phrases" a message must all contain, a OR b for either, -word to exclude"#
+        )]
+        query: String,
+        #[structopt(long = "chat-type", help = "e.g. WeChat, SMS, QQ")]
+        chat_type: Option<String>,
+        #[structopt(long = "owner-id")]
+        owner_id: Option<String>,
+        #[structopt(long = "group-id", help = "the chat, e.g. a wxid or phone number")]
+        group_id: Option<String>,
+        #[structopt(long = "sender", help = "sender id or name")]
+        sender: Option<String>,
+        #[structopt(long = "has-attachment", help = "only messages with attachments")]
+        has_attachment: bool,
+        #[structopt(
+            short = "C",
+            long = "context",
+            default_value = "0",
+            help = "messages to show before and after each result"
+        )]
+        context: usize,
+        #[structopt(short = "n", long = "limit", default_value = "50")]
+        limit: usize,
+        #[structopt(
+            short = "f",
+            long = "format",
+            default_value = "text",
+            possible_values = &["text", "json"])]
+        format: String,
+    },
+    #[structopt(
+        name = "run",
+        about = "run a named profile of the config file, options after -- are added to it"
+    )]
+    Run {
+        profile: String,
+        #[structopt(last = true)]
+        args: Vec<String>,
+    },
 }
 
 #[derive(StructOpt)]
 struct Args {
     #[structopt(flatten)]
     pub verbosity: Verbosity,
+    #[structopt(long = "log-file", global = true, help = "also append logs to a file")]
+    log_file: Option<PathBuf>,
+    #[structopt(
+        long = "log-format",
+        global = true,
+        default_value = "text",
+        possible_values = &["text", "json"]
+    )]
+    log_format: String,
+    #[structopt(
+        long = "since",
+        global = true,
+        parse(try_from_str = parse_date),
+        help = "only import messages since date, e.g. 2023-01-01"
+    )]
+    since: Option<NaiveDate>,
+    #[structopt(
+        long = "until",
+        global = true,
+        parse(try_from_str = parse_date),
+        help = "only import messages until date, inclusive"
+    )]
+    until: Option<NaiveDate>,
+    #[structopt(
+        long = "dry-run",
+        global = true,
+        help = "compare with record.db and report changes without writing"
+    )]
+    dry_run: bool,
+    #[structopt(
+        long = "report",
+        global = true,
+        requires = "dry-run",
+        help = "also write the dry run report as json"
+    )]
+    report: Option<PathBuf>,
+    #[structopt(
+        short = "j",
+        long = "jobs",
+        global = true,
+        default_value = "0",
+        help = "worker threads to transform records, 0 uses all cores"
+    )]
+    jobs: usize,
+    #[structopt(
+        long = "batch-size",
+        global = true,
+        default_value = "1000",
+        help = "records written between checkpoints"
+    )]
+    batch_size: usize,
+    #[structopt(
+        long = "resume",
+        global = true,
+        conflicts_with = "dry-run",
+        help = "skip sources and chats an interrupted run already imported"
+    )]
+    resume: bool,
+    #[structopt(
+        long = "summary",
+        global = true,
+        conflicts_with = "dry-run",
+        help = "also write the import summary as json"
+    )]
+    summary: Option<PathBuf>,
+    #[structopt(
+        long = "db",
+        global = true,
+        default_value = "record.db",
+        help = "database to import into"
+    )]
+    db: PathBuf,
+    #[structopt(
+        long = "password-env",
+        global = true,
+        help = "environment variable holding the backup password, instead of asking"
+    )]
+    password_env: Option<String>,
+    #[structopt(
+        long = "config",
+        global = true,
+        default_value = "chat_importer.toml",
+        help = "config file with the profiles of `run`"
+    )]
+    config: PathBuf,
     #[structopt(subcommand)]
     cmd: SubCommand,
 }
 
+/// A named import in the config file, `[profiles.<name>]`.
+///
+/// `command` is the subcommand, `paths` its inputs, every other key is the long
+/// name of an option: strings and numbers are its value, `true` sets a flag and
+/// arrays are joined by commas. Options given on the command line win.
+#[derive(Deserialize)]
+struct Profile {
+    command: String,
+    #[serde(default)]
+    paths: Vec<PathBuf>,
+    #[serde(flatten)]
+    options: BTreeMap<String, toml::Value>,
+}
+
+#[derive(Deserialize)]
+struct Config {
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+}
+
+impl Profile {
+    fn load(config: &Path, name: &str) -> Result<Self, String> {
+        let data = std::fs::read_to_string(config)
+            .map_err(|e| format!("Cannot read config {}: {}", config.display(), e))?;
+        let mut config = toml::from_str::<Config>(&data)
+            .map_err(|e| format!("Cannot parse config {}: {}", config.display(), e))?;
+        let profile = config.profiles.remove(name).ok_or_else(|| {
+            format!(
+                "Profile {} not found, available: {}",
+                name,
+                config
+                    .profiles
+                    .keys()
+                    .cloned()
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            )
+        })?;
+        if profile.command == "run" {
+            return Err("A profile cannot run another profile".into());
+        }
+        Ok(profile)
+    }
+
+    /// Command line of the profile, leaving out the options `given` on the command line.
+    fn to_args(&self, given: impl Fn(&str) -> bool) -> Result<Vec<String>, String> {
+        let mut args = vec![self.command.clone()];
+        for (key, value) in self.options.iter() {
+            if given(key) {
+                continue;
+            }
+            let value = match value {
+                toml::Value::Boolean(true) => None,
+                toml::Value::Boolean(false) => continue,
+                toml::Value::String(s) => Some(s.clone()),
+                toml::Value::Integer(i) => Some(i.to_string()),
+                toml::Value::Array(values) => Some(
+                    values
+                        .iter()
+                        .map(|v| v.as_str().map(|s| s.to_string()).unwrap_or(v.to_string()))
+                        .collect::<Vec<_>>()
+                        .join(","),
+                ),
+                value => return Err(format!("Unsupported value of {}: {}", key, value)),
+            };
+            args.push(format!("--{}", key));
+            args.extend(value);
+        }
+        Ok(args)
+    }
+}
+
+/// The value given on the command line, or else the one of the profile.
+fn pick<T>(given: bool, parsed: T, profile: T) -> T {
+    if given {
+        parsed
+    } else {
+        profile
+    }
+}
+
 impl Args {
+    /// Parse the command line, expanding `run <profile>` into the profile's command.
+    ///
+    /// Options are looked up by their name in the parsed matches, which is also
+    /// their long name, so short flags override the profile as well.
+    fn parse() -> Self {
+        Self::parse_from(std::env::args().collect())
+    }
+
+    fn parse_from(argv: Vec<String>) -> Self {
+        let matches = Self::clap().get_matches_from(&argv);
+        let args = Self::from_clap(&matches);
+        let (profile, extra) = match &args.cmd {
+            SubCommand::Run {
+                profile,
+                args: extra,
+            } => (profile, extra),
+            _ => return args,
+        };
+        let exit = |e: String| -> ! {
+            structopt::clap::Error::with_description(&e, structopt::clap::ErrorKind::InvalidValue)
+                .exit()
+        };
+        let profile = Profile::load(&args.config, profile).unwrap_or_else(|e| exit(e));
+        let bin = argv.first().cloned().unwrap_or_default();
+        let command_line = |options: Vec<String>| {
+            once(bin.clone())
+                .chain(options)
+                .chain(extra.iter().cloned())
+                .chain(
+                    profile
+                        .paths
+                        .iter()
+                        .map(|p| p.to_string_lossy().to_string()),
+                )
+        };
+        // options after `--` belong to the profile's command
+        let extra_matches = Self::clap()
+            .get_matches_from_safe(command_line(vec![profile.command.clone()]))
+            .unwrap_or_else(|e| e.exit());
+        let given_before = |key: &str| {
+            [Some(&matches), matches.subcommand_matches("run")]
+                .iter()
+                .flatten()
+                .any(|m| m.occurrences_of(key) > 0)
+        };
+        let given = |key: &str| {
+            given_before(key)
+                || [
+                    Some(&extra_matches),
+                    extra_matches.subcommand_matches(&profile.command),
+                ]
+                .iter()
+                .flatten()
+                .any(|m| m.occurrences_of(key) > 0)
+        };
+        let options = profile.to_args(given).unwrap_or_else(|e| exit(e));
+        let expanded = Self::from_iter(command_line(options));
+        args.merge(expanded, given_before)
+    }
+
+    /// The profile's command with its global options, except those given before it.
+    fn merge(self, profile: Self, given: impl Fn(&str) -> bool) -> Self {
+        Self {
+            verbosity: pick(given("verbosity"), self.verbosity, profile.verbosity),
+            log_file: pick(given("log-file"), self.log_file, profile.log_file),
+            log_format: pick(given("log-format"), self.log_format, profile.log_format),
+            since: pick(given("since"), self.since, profile.since),
+            until: pick(given("until"), self.until, profile.until),
+            dry_run: pick(given("dry-run"), self.dry_run, profile.dry_run),
+            report: pick(given("report"), self.report, profile.report),
+            jobs: pick(given("jobs"), self.jobs, profile.jobs),
+            batch_size: pick(given("batch-size"), self.batch_size, profile.batch_size),
+            resume: pick(given("resume"), self.resume, profile.resume),
+            summary: pick(given("summary"), self.summary, profile.summary),
+            db: pick(given("db"), self.db, profile.db),
+            password_env: pick(
+                given("password-env"),
+                self.password_env,
+                profile.password_env,
+            ),
+            config: self.config,
+            cmd: profile.cmd,
+        }
+    }
+
     fn get_paths(&self) -> Vec<PathBuf> {
         match &self.cmd {
-            SubCommand::QQ { path, .. } => path
+            SubCommand::QQ { path, .. } | SubCommand::TextLog { path, .. } => path
                 .iter()
                 .flat_map(|path| {
                     WalkDir::new(path)
@@ -70,10 +527,35 @@ impl Args {
                 })
                 .filter(|p| p.is_file())
                 .collect(),
-            SubCommand::WeChat { path, .. } | SubCommand::SMS { path, .. } => path
+            SubCommand::WeChat { path, .. }
+            | SubCommand::SMS { path, .. }
+            | SubCommand::Inspect { path, .. } => path
+                .iter()
+                .filter(|p| p.is_dir() || Backup::is_backup(p))
+                .cloned()
+                .collect(),
+            SubCommand::Auto { path, .. } => path.clone(),
+            // expanded by `Args::parse`
+            SubCommand::Run { .. } | SubCommand::Export { .. } | SubCommand::Search { .. } => {
+                vec![]
+            }
+            SubCommand::Slack { path, .. } => path
+                .iter()
+                .filter(|p| p.is_dir() || p.extension().is_some_and(|ext| ext == "zip"))
+                .cloned()
+                .collect(),
+            // folders are imported as a whole so eml replies can be threaded together
+            SubCommand::Mail { path, .. } => path
                 .iter()
-                .map(PathBuf::from)
-                .filter(PathBuf::is_dir)
+                .flat_map(|path| {
+                    let mboxes = path.is_dir().then(|| {
+                        WalkDir::new(path)
+                            .into_iter()
+                            .filter_map(|e| e.map(|item| item.into_path()).ok())
+                            .filter(|p| p.is_file() && p.ext_str().eq_ignore_ascii_case("mbox"))
+                    });
+                    once(path.clone()).chain(mboxes.into_iter().flatten())
+                })
                 .collect(),
         }
     }
@@ -82,6 +564,20 @@ impl Args {
     }
 }
 
+/// First and last millisecond of the local day.
+pub fn day_bounds(date: &NaiveDate) -> (i64, i64) {
+    let start = Local
+        .from_local_datetime(&date.and_time(NaiveTime::MIN))
+        .earliest()
+        .map(|t| t.timestamp_millis())
+        .unwrap_or_else(|| date.and_time(NaiveTime::MIN).and_utc().timestamp_millis());
+    (start, start + 24 * 60 * 60 * 1000 - 1)
+}
+
+fn parse_date(src: &str) -> Result<NaiveDate, chrono::ParseError> {
+    NaiveDate::parse_from_str(src, "%Y-%m-%d")
+}
+
 fn check_path<S: AsRef<str>>(src: S) -> Result<PathBuf, Error> {
     let path = PathBuf::from(src.as_ref());
     let path = path.absolutize()?;
@@ -96,13 +592,58 @@ fn check_path<S: AsRef<str>>(src: S) -> Result<PathBuf, Error> {
 }
 
 lazy_static! {
-    static ref ARGS: Args = Args::from_args();
+    static ref ARGS: Args = Args::parse();
 }
 
 pub fn get_log_level() -> Level {
     ARGS.get_log_level()
 }
 
+pub fn get_db() -> PathBuf {
+    ARGS.db.clone()
+}
+
+pub fn get_password_env() -> Option<String> {
+    ARGS.password_env.clone()
+}
+
+pub fn get_log_file() -> Option<PathBuf> {
+    ARGS.log_file.clone()
+}
+
+pub fn get_log_json() -> bool {
+    ARGS.log_format == "json"
+}
+
+/// `--since` / `--until` as inclusive milliseconds.
+pub fn get_date_range() -> (Option<i64>, Option<i64>) {
+    (
+        ARGS.since.as_ref().map(|d| day_bounds(d).0),
+        ARGS.until.as_ref().map(|d| day_bounds(d).1),
+    )
+}
+
+/// `Some(report path)` on `--dry-run`.
+pub fn get_dry_run() -> Option<Option<PathBuf>> {
+    ARGS.dry_run.then(|| ARGS.report.clone())
+}
+
+pub fn get_batch_size() -> usize {
+    ARGS.batch_size
+}
+
+pub fn get_resume() -> bool {
+    ARGS.resume
+}
+
+pub fn get_summary() -> Option<PathBuf> {
+    ARGS.summary.clone()
+}
+
+pub fn get_jobs() -> usize {
+    ARGS.jobs
+}
+
 pub fn get_paths() -> Vec<PathBuf> {
     ARGS.get_paths()
 }
@@ -110,3 +651,73 @@ pub fn get_paths() -> Vec<PathBuf> {
 pub fn get_cmd() -> &'static SubCommand {
     &ARGS.cmd
 }
+
+#[test]
+fn test_profile_args() {
+    let config: Config = toml::from_str(
+        r#"
+        [profiles.family]
+        command = "wc"
+        paths = ["/backups/iphone"]
+        accounts = ["wxid_a", "me"]
+        groups = true
+        private = false
+        min-messages = 100
+        db = "family.db"
+        "#,
+    )
+    .unwrap();
+    let profile = &config.profiles["family"];
+    assert_eq!(profile.paths, vec![PathBuf::from("/backups/iphone")]);
+    assert_eq!(
+        profile.to_args(|key| key == "db").unwrap(),
+        vec![
+            "wc",
+            "--accounts",
+            "wxid_a,me",
+            "--groups",
+            "--min-messages",
+            "100"
+        ]
+    );
+}
+
+#[test]
+fn test_run_profile() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("chat_importer.toml");
+    std::fs::write(
+        &config,
+        format!(
+            "[profiles.phone]\ncommand = \"sms\"\npaths = [{:?}]\nowner = \"profile\"\ndb = \"phone.db\"\n",
+            dir.path()
+        ),
+    )
+    .unwrap();
+    let args = Args::parse_from(
+        [
+            "chat_importer",
+            "--config",
+            config.to_str().unwrap(),
+            "--jobs",
+            "2",
+            "run",
+            "phone",
+            "--",
+            "-o",
+            "me",
+        ]
+        .iter()
+        .map(|s| s.to_string())
+        .collect(),
+    );
+    assert_eq!(args.jobs, 2);
+    assert_eq!(args.db, PathBuf::from("phone.db"));
+    match args.cmd {
+        SubCommand::SMS { owner, path, .. } => {
+            assert_eq!(owner, "me");
+            assert_eq!(path.len(), 1);
+        }
+        _ => panic!("profile not expanded"),
+    }
+}
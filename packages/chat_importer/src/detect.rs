@@ -0,0 +1,246 @@
+use crate::matcher::{ChatFilter, ExportType, IosSource, TextLogProfile};
+use ibackuptool2::Backup;
+use log::{info, warn};
+use path_ext::PathExt;
+use std::fs::{read_dir, File};
+use std::io::Read;
+use std::path::{Path, PathBuf};
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+/// What `auto` recognized at a path.
+enum Detected {
+    Inputs(Vec<ExportType<PathBuf>>),
+    Nothing,
+}
+
+/// Extensions of the files `auto` may import, other files don't keep a
+/// folder of emls from being imported as one mail folder.
+const INPUT_EXTENSIONS: [&str; 14] = [
+    "mht", "mhtml", "html", "htm", "zip", "mbox", "eml", "txt", "tar", "gz", "tgz", "zst", "tzst",
+    "db",
+];
+
+/// Where the mobile home folder is found in an extracted filesystem.
+const MOBILE_HOMES: [&str; 3] = ["", "var/mobile", "private/var/mobile"];
+
+/// Walk `root` and route every input found to its matcher, a folder that is
+/// an input as a whole is not walked into.
+pub fn detect_inputs(root: &Path, owner: &str) -> Vec<ExportType<PathBuf>> {
+    let mut inputs = vec![];
+    let mut walker = WalkDir::new(root).into_iter();
+    while let Some(entry) = walker.next() {
+        let entry = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                warn!("Cannot read {}: {}", root.display(), e);
+                continue;
+            }
+        };
+        let path = entry.path();
+        match detect(path, owner) {
+            Detected::Inputs(found) => {
+                info!("Detected {}: {}", name_of(&found), path.display());
+                if entry.file_type().is_dir() {
+                    walker.skip_current_dir();
+                }
+                inputs.extend(found);
+            }
+            Detected::Nothing => {}
+        }
+    }
+    inputs
+}
+
+fn name_of(inputs: &[ExportType<PathBuf>]) -> String {
+    inputs
+        .iter()
+        .map(|input| match input {
+            ExportType::WindowsQQ(..) => "qq",
+            ExportType::iOSWeChat(..) => "wechat",
+            ExportType::iOSSMS(..) => "sms",
+            ExportType::SlackExport(..) => "slack",
+            ExportType::Mbox(..) | ExportType::Eml(..) => "mail",
+            ExportType::TextLog(..) => "text log",
+        })
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+/// Both matchers share the backup, it is opened once.
+fn backup(path: &Path, owner: &str) -> Detected {
+    let source = IosSource::new(path);
+    Detected::Inputs(vec![
+        ExportType::iOSWeChat(source.clone(), None, ChatFilter::default()),
+        ExportType::iOSSMS(source, owner.into()),
+    ])
+}
+
+fn detect(path: &Path, owner: &str) -> Detected {
+    if path.is_dir() {
+        return if path.join("Manifest.plist").is_file() {
+            backup(path, owner)
+        } else if path.join("users.json").is_file() && path.join("channels.json").is_file() {
+            Detected::Inputs(vec![ExportType::SlackExport(path.into(), None)])
+        } else if let Some(found) = extracted_ios(path, owner) {
+            Detected::Inputs(found)
+        } else if is_mail_folder(path) {
+            // emls of a folder are threaded together
+            Detected::Inputs(vec![ExportType::Eml(path.into(), owner.into())])
+        } else {
+            Detected::Nothing
+        };
+    }
+    match path.ext_str().to_lowercase().as_str() {
+        "mht" | "mhtml" => Detected::Inputs(vec![ExportType::WindowsQQ(path.into(), owner.into())]),
+        "html" | "htm" if is_qq_html(path) => {
+            Detected::Inputs(vec![ExportType::WindowsQQ(path.into(), owner.into())])
+        }
+        "zip" if is_slack_zip(path) => {
+            Detected::Inputs(vec![ExportType::SlackExport(path.into(), None)])
+        }
+        "mbox" => Detected::Inputs(vec![ExportType::Mbox(path.into(), owner.into())]),
+        "eml" => Detected::Inputs(vec![ExportType::Eml(path.into(), owner.into())]),
+        "txt" => match TextLogProfile::detect(path) {
+            Some(profile) => Detected::Inputs(vec![ExportType::TextLog(
+                path.into(),
+                profile,
+                owner.into(),
+            )]),
+            None => Detected::Nothing,
+        },
+        _ if Backup::is_backup_archive(path) => backup(path, owner),
+        _ => Detected::Nothing,
+    }
+}
+
+/// Extracted iOS data: a WeChat app container, the mobile home folder or a
+/// filesystem dump holding them. Only the known locations are looked at.
+fn extracted_ios(path: &Path, owner: &str) -> Option<Vec<ExportType<PathBuf>>> {
+    let homes = MOBILE_HOMES
+        .iter()
+        .map(|home| path.join(home))
+        .filter(|home| home.is_dir())
+        .collect::<Vec<_>>();
+    let wechat = is_wechat_container(path)
+        || homes.iter().any(|home| {
+            read_dir(home.join("Containers/Data/Application")).is_ok_and(|apps| {
+                apps.filter_map(|e| e.ok())
+                    .any(|app| is_wechat_container(&app.path()))
+            })
+        });
+    let sms = homes
+        .iter()
+        .any(|home| home.join("Library/SMS/sms.db").is_file());
+    let source = IosSource::new(path);
+    let mut found = vec![];
+    if wechat {
+        found.push(ExportType::iOSWeChat(
+            source.clone(),
+            None,
+            ChatFilter::default(),
+        ));
+    }
+    if sms {
+        found.push(ExportType::iOSSMS(source, owner.into()));
+    }
+    (!found.is_empty()).then_some(found)
+}
+
+/// WeChat keeps the databases of every account in `Documents/<account>/DB`.
+fn is_wechat_container(path: &Path) -> bool {
+    read_dir(path.join("Documents")).is_ok_and(|accounts| {
+        accounts
+            .filter_map(|e| e.ok())
+            .any(|account| account.path().join("DB/MM.sqlite").is_file())
+    })
+}
+
+/// A folder of emls and nothing else to import, emls next to other inputs
+/// are detected one by one so the folder is still walked into. Only names
+/// are checked, files in the folder are not opened.
+fn is_mail_folder(path: &Path) -> bool {
+    let entries = match read_dir(path) {
+        Ok(entries) => entries
+            .filter_map(|e| e.ok().map(|e| e.path()))
+            .collect::<Vec<_>>(),
+        Err(_) => return false,
+    };
+    let ext = |path: &Path| path.ext_str().to_lowercase();
+    entries.iter().any(|path| ext(path) == "eml")
+        && entries.iter().all(|path| {
+            path.is_file()
+                && (ext(path) == "eml" || !INPUT_EXTENSIONS.contains(&ext(path).as_str()))
+        })
+}
+
+/// QQ's html export, the header names the message group and object.
+fn is_qq_html(path: &Path) -> bool {
+    let mut head = vec![0; 64 * 1024];
+    File::open(path)
+        .and_then(|mut file| file.read(&mut head))
+        .is_ok_and(|len| String::from_utf8_lossy(&head[..len]).contains("消息对象:"))
+}
+
+fn is_slack_zip(path: &Path) -> bool {
+    File::open(path)
+        .ok()
+        .and_then(|file| ZipArchive::new(file).ok())
+        .is_some_and(|archive| {
+            archive
+                .file_names()
+                .any(|name| name.ends_with("users.json"))
+        })
+}
+
+#[test]
+fn test_detect_inputs() {
+    use std::fs::{create_dir_all, write};
+    let dir = tempfile::tempdir().unwrap();
+    let root = dir.path();
+    create_dir_all(root.join("slack/general")).unwrap();
+    write(root.join("slack/users.json"), "[]").unwrap();
+    write(root.join("slack/channels.json"), "[]").unwrap();
+    write(root.join("slack/general/2020-01-01.json"), "[]").unwrap();
+    create_dir_all(root.join("mails")).unwrap();
+    write(root.join("mails/a.eml"), "").unwrap();
+    write(root.join("mails/b.eml"), "").unwrap();
+    // a stray mail doesn't hide the inputs next to it
+    write(root.join("stray.eml"), "").unwrap();
+    let mut zip = zip::ZipWriter::new(std::fs::File::create(root.join("photos.zip")).unwrap());
+    zip.start_file("Manifest.plist", zip::write::SimpleFileOptions::default())
+        .unwrap();
+    zip.finish().unwrap();
+    write(root.join("chat.txt"), "[2023-01-02 03:04] Alice: hi\n").unwrap();
+    write(root.join("notes.txt"), "nothing to see\n").unwrap();
+    write(root.join("mmssms.db"), "").unwrap();
+    // extracted filesystem dump and a single app container
+    let home = root.join("dump/private/var/mobile");
+    create_dir_all(home.join("Library/SMS")).unwrap();
+    write(home.join("Library/SMS/sms.db"), "").unwrap();
+    let app = home.join("Containers/Data/Application/0000/Documents/account/DB");
+    create_dir_all(&app).unwrap();
+    write(app.join("MM.sqlite"), "").unwrap();
+    create_dir_all(root.join("wechat/Documents/account/DB")).unwrap();
+    write(root.join("wechat/Documents/account/DB/MM.sqlite"), "").unwrap();
+    let mut found = detect_inputs(root, "me")
+        .iter()
+        .map(|input| {
+            let (name, path) = input.source();
+            (name, path.strip_prefix(root).unwrap().to_path_buf())
+        })
+        .collect::<Vec<_>>();
+    found.sort();
+    assert_eq!(
+        found,
+        vec![
+            ("Eml", PathBuf::from("mails")),
+            ("Eml", PathBuf::from("stray.eml")),
+            ("Slack", PathBuf::from("slack")),
+            ("TextLog", PathBuf::from("chat.txt")),
+            ("iOSSMS", PathBuf::from("dump")),
+            ("iOSWeChat", PathBuf::from("dump")),
+            ("iOSWeChat", PathBuf::from("wechat")),
+        ]
+    );
+}
@@ -0,0 +1,149 @@
+use crate::matcher::SqliteMetadataDiffer;
+use crate::summary::existing_metadata;
+use anyhow::Result;
+use gchdb::{Blob, RecordType, SqliteChatRecorder};
+use log::info;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::{BTreeMap, HashSet};
+use std::fs::write;
+use std::path::Path;
+
+#[derive(Serialize)]
+pub struct MetadataUpdate {
+    pub record: String,
+    pub changes: Vec<String>,
+}
+
+/// What an import would change in `record.db`.
+#[derive(Default, Serialize)]
+pub struct DryRunReport {
+    pub new_records: usize,
+    pub updated_metadata: usize,
+    /// already imported with the same metadata
+    pub unchanged: usize,
+    /// rows without a record to compare
+    pub skipped: usize,
+    pub new_blobs: usize,
+    pub new_blob_bytes: usize,
+    /// new records per "chat_type group_id"
+    pub new_by_chat: BTreeMap<String, usize>,
+    pub updates: Vec<MetadataUpdate>,
+}
+
+/// Compares records with `record.db` instead of writing them.
+pub struct DryRun {
+    conn: Connection,
+    /// blobs new in this run, so attachments shared by records count once
+    blobs: RefCell<HashSet<i64>>,
+    report: RefCell<DryRunReport>,
+}
+
+impl DryRun {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            conn: Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?,
+            blobs: RefCell::new(HashSet::new()),
+            report: RefCell::new(DryRunReport::default()),
+        })
+    }
+
+    pub fn check(
+        &self,
+        recorder: &SqliteChatRecorder,
+        record_type: &RecordType,
+        differ: Option<SqliteMetadataDiffer>,
+    ) -> Result<()> {
+        let mut report = self.report.borrow_mut();
+        let record = match record_type.get_record() {
+            Some(record) => record,
+            None => {
+                report.skipped += 1;
+                return Ok(());
+            }
+        };
+        let existing = existing_metadata(&self.conn, record)?;
+        let empty = Default::default();
+        let attaches = record_type.get_attaches().unwrap_or(&empty);
+        match (existing, &record.metadata) {
+            (None, _) => {
+                report.new_records += 1;
+                *report
+                    .new_by_chat
+                    .entry(format!("{} {}", record.chat_type, record.group_id))
+                    .or_default() += 1;
+            }
+            (Some(Some(old)), Some(new)) if &old != new => {
+                report.updated_metadata += 1;
+                let changes = differ
+                    .map(|differ| differ(recorder, attaches, &old, new))
+                    .unwrap_or_else(|| vec!["metadata replaced".into()]);
+                report.updates.push(MetadataUpdate {
+                    record: record_type.display(),
+                    changes,
+                });
+            }
+            (Some(None), Some(_)) => {
+                report.updated_metadata += 1;
+                report.updates.push(MetadataUpdate {
+                    record: record_type.display(),
+                    changes: vec!["metadata added".into()],
+                });
+            }
+            _ => report.unchanged += 1,
+        }
+        let mut blobs = self.blobs.borrow_mut();
+        for data in attaches.values() {
+            let hash = Blob::new(data.clone()).hash;
+            if blobs.contains(&hash) {
+                continue;
+            }
+            let exists = self
+                .conn
+                .query_row("SELECT 1 FROM blobs WHERE hash = ?1", params![hash], |_| {
+                    Ok(())
+                })
+                .optional()?
+                .is_some();
+            if !exists {
+                blobs.insert(hash);
+                report.new_blobs += 1;
+                report.new_blob_bytes += data.len();
+            }
+        }
+        Ok(())
+    }
+
+    /// Print the report, and write it as json when a path is given.
+    pub fn report<P: AsRef<Path>>(&self, path: Option<P>) -> Result<()> {
+        let report = self.report.borrow();
+        for update in report.updates.iter() {
+            for change in update.changes.iter() {
+                println!("update {}: {}", update.record, change);
+            }
+        }
+        for (chat, count) in report.new_by_chat.iter() {
+            println!("new {}: {} records", chat, count);
+        }
+        println!(
+            "dry run: {} new records, {} metadata updates, {} unchanged, {} skipped, {} new blobs ({} bytes)",
+            report.new_records,
+            report.updated_metadata,
+            report.unchanged,
+            report.skipped,
+            report.new_blobs,
+            report.new_blob_bytes
+        );
+        if let Some(path) = path {
+            write(&path, serde_json::to_vec_pretty(&*report)?)?;
+            info!("Dry run report written to {}", path.as_ref().display());
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,186 @@
+use super::{display, format_time, BlobWriter, Media};
+use crate::store::{ChatKey, Store, StoredRecord};
+use anyhow::Result;
+use htmlescape::encode_minimal as escape;
+use std::fmt::Write;
+use std::fs::write;
+use std::path::Path;
+
+const STYLE: &str = r#"
+body { font-family: sans-serif; max-width: 860px; margin: 0 auto; padding: 1em; background: #f3f3f3; }
+.msg { background: #fff; border-radius: 6px; margin: 8px 0; padding: 8px 12px; }
+.msg.own { background: #e2f7d4; }
+.head { color: #888; font-size: 0.85em; margin-bottom: 4px; }
+.head .sender { color: #333; font-weight: bold; }
+.content { white-space: pre-wrap; word-wrap: break-word; }
+.msg img { max-width: 320px; max-height: 320px; display: block; margin-top: 4px; }
+.card { border: 1px solid #ddd; border-radius: 4px; padding: 6px 10px; margin-top: 4px; }
+.card .desc { color: #666; font-size: 0.9em; }
+.card .app { color: #999; font-size: 0.8em; }
+nav { text-align: center; margin: 1em 0; }
+nav a, nav span { margin: 0 4px; }
+"#;
+
+fn page_name(page: usize) -> String {
+    if page == 0 {
+        "index.html".into()
+    } else {
+        format!("page-{}.html", page + 1)
+    }
+}
+
+fn nav(page: usize, pages: usize) -> String {
+    if pages < 2 {
+        return String::new();
+    }
+    let mut nav = String::from("<nav>");
+    if page > 0 {
+        let _ = write!(nav, r#"<a href="{}">&laquo;</a>"#, page_name(page - 1));
+    }
+    for i in 0..pages {
+        // first, last and the pages around the current one
+        if i == 0 || i + 1 == pages || i.abs_diff(page) <= 3 {
+            if i == page {
+                let _ = write!(nav, "<span>{}</span>", i + 1);
+            } else {
+                let _ = write!(nav, r#"<a href="{}">{}</a>"#, page_name(i), i + 1);
+            }
+        } else if i.abs_diff(page) == 4 {
+            nav.push_str("<span>…</span>");
+        }
+    }
+    if page + 1 < pages {
+        let _ = write!(nav, r#"<a href="{}">&raquo;</a>"#, page_name(page + 1));
+    }
+    nav.push_str("</nav>");
+    nav
+}
+
+/// Links of imported records are only followed for web pages, `javascript:` and
+/// other schemes are shown as text.
+fn is_web_link(url: &str) -> bool {
+    let url = url.trim_start().to_ascii_lowercase();
+    url.starts_with("http://") || url.starts_with("https://")
+}
+
+/// WeChat app messages keep their title, description, link and app in the metadata.
+fn card(record: &StoredRecord) -> Option<String> {
+    let title = record.tag("title");
+    let url = record.tag("url");
+    if title.is_none() && url.is_none() {
+        return None;
+    }
+    let title = escape(title.or(url).unwrap_or_default());
+    let mut card = String::from(r#"<div class="card">"#);
+    match url.filter(|url| is_web_link(url)) {
+        Some(url) => {
+            let _ = write!(card, r#"<a href="{}">{}</a>"#, escape(url), title);
+        }
+        None => card.push_str(&title),
+    }
+    if let Some(desc) = record.tag("description") {
+        let _ = write!(card, r#"<div class="desc">{}</div>"#, escape(desc));
+    }
+    if let Some(app) = record.tag("app") {
+        let _ = write!(card, r#"<div class="app">{}</div>"#, escape(app));
+    }
+    card.push_str("</div>");
+    Some(card)
+}
+
+fn message(record: &StoredRecord, owner_id: &str, blobs: &mut BlobWriter) -> Result<String> {
+    let mut html = format!(
+        r#"<div class="msg{}" id="r{}"><div class="head"><span class="sender" title="{}">{}</span> {}</div>"#,
+        if record.sender_id == owner_id {
+            " own"
+        } else {
+            ""
+        },
+        record.id,
+        escape(&record.sender_id),
+        escape(record.sender()),
+        format_time(record.timestamp),
+    );
+    let card = card(record);
+    // the content of app messages is a placeholder for their card
+    if card.is_none() || record.mtype() != Some("CustomApp") {
+        let _ = write!(
+            html,
+            r#"<div class="content">{}</div>"#,
+            escape(&record.content)
+        );
+    }
+    html.push_str(&card.unwrap_or_default());
+    for blob in blobs.save(record)? {
+        let path = escape(&blob.path);
+        let _ = match blob.media {
+            Media::Image => write!(
+                html,
+                r#"<a href="{0}"><img src="{0}" loading="lazy"></a>"#,
+                path
+            ),
+            Media::Video => write!(html, r#"<div><a href="{}">&#9654; video</a></div>"#, path),
+            Media::Voice => write!(html, r#"<div><a href="{}">&#128266; voice</a></div>"#, path),
+            Media::File => write!(
+                html,
+                r#"<div><a href="{}">{}</a></div>"#,
+                path,
+                escape(&blob.name)
+            ),
+        };
+    }
+    html.push_str("</div>\n");
+    Ok(html)
+}
+
+/// A static viewer of the chat, `page_size` records per page.
+pub fn export_chat(
+    store: &Store,
+    chat: &ChatKey,
+    records: &[StoredRecord],
+    dir: &Path,
+    page_size: usize,
+) -> Result<()> {
+    let mut blobs = BlobWriter::new(store, dir);
+    let pages = records.chunks(page_size.max(1)).collect::<Vec<_>>();
+    let title = escape(&display(chat));
+    for (page, chunk) in pages.iter().enumerate() {
+        let nav = nav(page, pages.len());
+        let mut html = format!(
+            "<!DOCTYPE html>\n<html><head><meta charset=\"utf-8\"><title>{}</title><style>{}</style></head><body>\n<h1>{}</h1>\n{}\n",
+            title, STYLE, title, nav
+        );
+        for record in chunk.iter() {
+            html.push_str(&message(record, &chat.owner_id, &mut blobs)?);
+        }
+        html.push_str(&nav);
+        html.push_str("\n</body></html>\n");
+        write(dir.join(page_name(page)), html)?;
+    }
+    Ok(())
+}
+
+#[test]
+fn test_card() {
+    let record = |url: &str| StoredRecord {
+        id: 1,
+        sender_id: "wxid_a".into(),
+        sender_name: "Alice".into(),
+        content: "[app]".into(),
+        timestamp: 0,
+        metadata: Some(serde_json::json!({
+            "mtype": "CustomApp",
+            "hash": { "title": "<b>news</b>", "url": url },
+        })),
+        attachments: vec![],
+    };
+    let web = record("https://example.com/?a=1&b=2");
+    assert_eq!(web.mtype(), Some("CustomApp"));
+    assert_eq!(
+        card(&web).unwrap(),
+        r#"<div class="card"><a href="https://example.com/?a=1&amp;b=2">&lt;b&gt;news&lt;/b&gt;</a></div>"#
+    );
+    let script = card(&record("javascript:alert(1)")).unwrap();
+    assert!(!script.contains("href"));
+    assert!(script.contains("&lt;b&gt;news&lt;/b&gt;"));
+}
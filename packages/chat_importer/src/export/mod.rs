@@ -0,0 +1,154 @@
+mod html;
+mod pdf;
+mod text;
+
+use crate::matcher::DateRange;
+use crate::store::{ChatKey, ChatQuery, Store, StoredRecord};
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use log::{info, warn};
+use serde::Serialize;
+use std::collections::HashSet;
+use std::fs::{create_dir_all, write};
+use std::path::{Path, PathBuf};
+
+pub struct ChatExportOptions {
+    pub query: ChatQuery,
+    pub range: DateRange,
+    pub out: PathBuf,
+    /// records per html page
+    pub page_size: usize,
+    /// ttf font for pdfs, the builtin ones only cover latin scripts
+    pub font: Option<PathBuf>,
+}
+
+/// What an attachment blob looks like, by its leading bytes.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Media {
+    Image,
+    Video,
+    Voice,
+    File,
+}
+
+pub fn sniff(data: &[u8]) -> (Media, &'static str) {
+    match data {
+        [0x89, b'P', b'N', b'G', ..] => (Media::Image, "png"),
+        [0xff, 0xd8, 0xff, ..] => (Media::Image, "jpg"),
+        [b'G', b'I', b'F', b'8', ..] => (Media::Image, "gif"),
+        [b'B', b'M', ..] => (Media::Image, "bmp"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => (Media::Image, "webp"),
+        [_, _, _, _, b'f', b't', b'y', b'p', ..] => (Media::Video, "mp4"),
+        [b'#', b'!', b'A', b'M', b'R', ..] => (Media::Voice, "amr"),
+        [b'#', b'!', b'S', b'I', b'L', b'K', ..]
+        | [0x02, b'#', b'!', b'S', b'I', b'L', b'K', ..] => (Media::Voice, "silk"),
+        [b'I', b'D', b'3', ..] | [0xff, 0xfb, ..] => (Media::Voice, "mp3"),
+        _ => (Media::File, "bin"),
+    }
+}
+
+/// Local date and time of a record timestamp.
+pub fn format_time(timestamp: i64) -> String {
+    Local
+        .timestamp_millis_opt(timestamp)
+        .single()
+        .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string())
+        .unwrap_or_else(|| timestamp.to_string())
+}
+
+/// An attachment written next to the transcript.
+#[derive(Serialize)]
+pub struct SavedBlob {
+    pub name: String,
+    /// relative to the chat's folder
+    pub path: String,
+    pub media: Media,
+}
+
+/// Writes the blobs of a chat to `attachments/`, each once.
+pub struct BlobWriter<'a> {
+    store: &'a Store,
+    dir: PathBuf,
+    written: HashSet<i64>,
+}
+
+impl<'a> BlobWriter<'a> {
+    pub fn new(store: &'a Store, chat_dir: &Path) -> Self {
+        Self {
+            store,
+            dir: chat_dir.join("attachments"),
+            written: HashSet::new(),
+        }
+    }
+
+    pub fn save(&mut self, record: &StoredRecord) -> Result<Vec<SavedBlob>> {
+        let mut saved = vec![];
+        for (name, hash) in record.blobs() {
+            let data = match self.store.blob(hash)? {
+                Some(data) => data,
+                None => continue,
+            };
+            let (media, ext) = sniff(&data);
+            // files keep the extension of their name
+            let ext = match Path::new(&name).extension().and_then(|e| e.to_str()) {
+                Some(name_ext) if media == Media::File => name_ext,
+                _ => ext,
+            };
+            let file = format!("{}.{}", hash, ext);
+            if self.written.insert(hash) {
+                create_dir_all(&self.dir)?;
+                write(self.dir.join(&file), &data)?;
+            }
+            saved.push(SavedBlob {
+                // wechat names attachments of app messages "attach:<file name>"
+                name: name.trim_start_matches("attach:").into(),
+                path: format!("attachments/{}", file),
+                media,
+            });
+        }
+        Ok(saved)
+    }
+}
+
+/// Export every chat matching the query into its own folder under `out`.
+pub fn export(db: &Path, format: &str, options: &ChatExportOptions) -> Result<()> {
+    let store = Store::open(db)?;
+    let chats = store.chats(&options.query)?;
+    if chats.is_empty() {
+        warn!("No chat matches {:?}", options.query);
+    }
+    for (chat, _) in chats {
+        let records = store.records(&chat, options.range)?;
+        if records.is_empty() {
+            continue;
+        }
+        let dir = options.out.join(chat.file_name());
+        create_dir_all(&dir)?;
+        match format {
+            "html" => html::export_chat(&store, &chat, &records, &dir, options.page_size)?,
+            "pdf" => pdf::export_chat(&store, &chat, &records, &dir, options.font.as_deref())?,
+            "txt" | "md" | "json" => text::export_chat(&store, &chat, &records, &dir, format)?,
+            _ => return Err(anyhow::anyhow!("Unknown export format: {}", format)),
+        }
+        info!(
+            "Exported {} records of {} to {}",
+            records.len(),
+            display(&chat),
+            dir.display()
+        );
+    }
+    Ok(())
+}
+
+pub fn display(chat: &ChatKey) -> String {
+    format!("{} {} {}", chat.chat_type, chat.owner_id, chat.group_id)
+}
+
+#[test]
+fn test_sniff() {
+    assert_eq!(sniff(b"\x89PNG\r\n\x1a\n").0, Media::Image);
+    assert_eq!(sniff(b"\0\0\0\x18ftypmp42").0, Media::Video);
+    assert_eq!(sniff(b"\x02#!SILK_V3"), (Media::Voice, "silk"));
+    assert_eq!(sniff(b"%PDF-1.4").0, Media::File);
+}
@@ -0,0 +1,423 @@
+use super::{display, format_time, BlobWriter, Media};
+use crate::store::{ChatKey, Provenance, Store, StoredRecord};
+use anyhow::{Context, Result};
+use chrono::Local;
+use image::codecs::jpeg::JpegEncoder;
+use image::ExtendedColorType;
+use log::warn;
+use printpdf::{
+    BuiltinFont, Color, ColorBits, ColorSpace, Greyscale, Image, ImageFilter, ImageTransform,
+    ImageXObject, IndirectFontRef, Mm, PdfDocument, PdfDocumentReference, PdfLayerIndex,
+    PdfLayerReference, PdfPageIndex, Px,
+};
+use std::fs::{read, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+// A4, in mm
+const PAGE_WIDTH: f32 = 210.0;
+const PAGE_HEIGHT: f32 = 297.0;
+const MARGIN: f32 = 15.0;
+const TEXT_WIDTH: f32 = PAGE_WIDTH - 2.0 * MARGIN;
+/// kept free at the bottom of every page for the page number and provenance
+const FOOTER: f32 = 22.0;
+const FOOTER_LINES: usize = 5;
+const IMAGE_SIZE: f32 = 80.0;
+/// larger images are scaled down before they are embedded
+const IMAGE_PIXELS: u32 = 1600;
+const MM_PER_PT: f32 = 25.4 / 72.0;
+
+#[derive(Clone, Copy)]
+enum Style {
+    Title,
+    Head,
+    Body,
+    Footer,
+}
+
+impl Style {
+    fn size(self) -> f32 {
+        match self {
+            Style::Title => 14.0,
+            Style::Head => 8.0,
+            Style::Body => 10.0,
+            Style::Footer => 7.0,
+        }
+    }
+
+    fn line_height(self) -> f32 {
+        self.size() * MM_PER_PT * 1.35
+    }
+}
+
+/// Width of text in mm, estimated: printpdf has no metrics of the builtin fonts,
+/// latin letters are counted as about half an em and wide characters as a full one.
+fn text_width(text: &str, size: f32) -> f32 {
+    text.chars()
+        .map(|c| if (c as u32) < 0x1100 { 0.55 } else { 1.0 })
+        .sum::<f32>()
+        * size
+        * MM_PER_PT
+}
+
+/// Break text into lines that fit `width`, at spaces where there are some.
+fn wrap(text: &str, size: f32, width: f32) -> Vec<String> {
+    let mut lines = vec![];
+    for paragraph in text.lines() {
+        let mut line = String::new();
+        for c in paragraph.chars() {
+            line.push(c);
+            if text_width(&line, size) <= width || line.chars().count() == 1 {
+                continue;
+            }
+            line.pop();
+            let rest = match line.rfind(' ').filter(|&i| i > 0) {
+                Some(i) => {
+                    let rest = line[i + 1..].to_string();
+                    line.truncate(i);
+                    rest
+                }
+                None => String::new(),
+            };
+            lines.push(std::mem::replace(&mut line, rest));
+            line.push(c);
+        }
+        lines.push(line);
+    }
+    lines
+}
+
+/// Lays out text and images top down, starting a new page when one is full.
+struct Writer {
+    doc: PdfDocumentReference,
+    regular: IndirectFontRef,
+    bold: IndirectFontRef,
+    pages: Vec<(PdfPageIndex, PdfLayerIndex)>,
+    layer: PdfLayerReference,
+    /// baseline of the last line, from the bottom of the page
+    y: f32,
+}
+
+impl Writer {
+    fn new(title: &str, font: Option<&Path>) -> Result<Self> {
+        let (doc, page, layer) =
+            PdfDocument::new(title, Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "messages");
+        let (regular, bold) = match font {
+            Some(path) => {
+                let font = doc.add_external_font(
+                    File::open(path)
+                        .with_context(|| format!("Cannot open font: {}", path.display()))?,
+                )?;
+                (font.clone(), font)
+            }
+            None => (
+                doc.add_builtin_font(BuiltinFont::Helvetica)?,
+                doc.add_builtin_font(BuiltinFont::HelveticaBold)?,
+            ),
+        };
+        let doc = doc.with_producer(format!("chat_importer {}", env!("CARGO_PKG_VERSION")));
+        let layer_ref = doc.get_page(page).get_layer(layer);
+        Ok(Self {
+            doc,
+            regular,
+            bold,
+            pages: vec![(page, layer)],
+            layer: layer_ref,
+            y: PAGE_HEIGHT - MARGIN,
+        })
+    }
+
+    fn new_page(&mut self) {
+        let (page, layer) = self
+            .doc
+            .add_page(Mm(PAGE_WIDTH), Mm(PAGE_HEIGHT), "messages");
+        self.layer = self.doc.get_page(page).get_layer(layer);
+        self.pages.push((page, layer));
+        self.y = PAGE_HEIGHT - MARGIN;
+    }
+
+    /// Move down by `height`, onto a new page when it doesn't fit this one.
+    fn advance(&mut self, height: f32) {
+        if self.y - height < MARGIN + FOOTER {
+            self.new_page();
+        }
+        self.y -= height;
+    }
+
+    fn font(&self, style: Style) -> &IndirectFontRef {
+        match style {
+            Style::Title | Style::Head => &self.bold,
+            Style::Body | Style::Footer => &self.regular,
+        }
+    }
+
+    fn text(&mut self, text: &str, style: Style) {
+        for line in wrap(text, style.size(), TEXT_WIDTH) {
+            self.advance(style.line_height());
+            let grey = matches!(style, Style::Head);
+            if grey {
+                self.layer
+                    .set_fill_color(Color::Greyscale(Greyscale::new(0.4, None)));
+            }
+            self.layer
+                .use_text(line, style.size(), Mm(MARGIN), Mm(self.y), self.font(style));
+            if grey {
+                self.layer
+                    .set_fill_color(Color::Greyscale(Greyscale::new(0.0, None)));
+            }
+        }
+    }
+
+    /// Embed an image at most `IMAGE_SIZE` wide and high, false if it cannot be decoded.
+    fn image(&mut self, data: &[u8]) -> bool {
+        let mut image = match image::load_from_memory(data) {
+            Ok(image) => image,
+            Err(_) => return false,
+        };
+        if image.width() > IMAGE_PIXELS || image.height() > IMAGE_PIXELS {
+            image = image.thumbnail(IMAGE_PIXELS, IMAGE_PIXELS);
+        }
+        let (width, height) = (image.width(), image.height());
+        // transparent pixels are put on white, the image is embedded as jpeg
+        let rgba = image.to_rgba8();
+        let pixels = rgba
+            .pixels()
+            .flat_map(|p| {
+                let alpha = p[3] as u16;
+                (0..3).map(move |i| ((p[i] as u16 * alpha + 255 * (255 - alpha)) / 255) as u8)
+            })
+            .collect::<Vec<_>>();
+        let mut jpeg = vec![];
+        if JpegEncoder::new_with_quality(&mut jpeg, 85)
+            .encode(&pixels, width, height, ExtendedColorType::Rgb8)
+            .is_err()
+        {
+            return false;
+        }
+        // as large as on a 96 dpi screen, but no larger than IMAGE_SIZE
+        let natural = |px: u32| px as f32 / 96.0 * 25.4;
+        let scale = (IMAGE_SIZE / natural(width))
+            .min(IMAGE_SIZE / natural(height))
+            .min(1.0);
+        self.advance(natural(height) * scale + 1.0);
+        let dpi = 300.0;
+        let at_dpi = |px: u32| px as f32 / dpi * 25.4;
+        Image::from(ImageXObject {
+            width: Px(width as usize),
+            height: Px(height as usize),
+            color_space: ColorSpace::Rgb,
+            bits_per_component: ColorBits::Bit8,
+            interpolate: true,
+            image_data: jpeg,
+            image_filter: Some(ImageFilter::DCT),
+            smask: None,
+            clipping_bbox: None,
+        })
+        .add_to_layer(
+            self.layer.clone(),
+            ImageTransform {
+                translate_x: Some(Mm(MARGIN)),
+                translate_y: Some(Mm(self.y)),
+                scale_x: Some(natural(width) * scale / at_dpi(width)),
+                scale_y: Some(natural(height) * scale / at_dpi(height)),
+                dpi: Some(dpi),
+                ..Default::default()
+            },
+        );
+        true
+    }
+
+    /// Page numbers and provenance at the bottom of every page, once all are laid out.
+    fn footers(&self, provenance: &[String]) {
+        let total = self.pages.len();
+        let style = Style::Footer;
+        let mut lines = provenance
+            .iter()
+            .flat_map(|line| wrap(line, style.size(), TEXT_WIDTH))
+            .collect::<Vec<_>>();
+        if lines.len() > FOOTER_LINES {
+            lines.truncate(FOOTER_LINES - 1);
+            lines.push("…".into());
+        }
+        for (i, &(page, layer)) in self.pages.iter().enumerate() {
+            let layer = self.doc.get_page(page).get_layer(layer);
+            let mut y = MARGIN;
+            let number = format!("Page {} of {}", i + 1, total);
+            layer.use_text(
+                number.as_str(),
+                style.size(),
+                Mm(PAGE_WIDTH - MARGIN - text_width(&number, style.size())),
+                Mm(y),
+                &self.regular,
+            );
+            for line in lines.iter().rev() {
+                y += style.line_height();
+                layer.use_text(
+                    line.as_str(),
+                    style.size(),
+                    Mm(MARGIN),
+                    Mm(y),
+                    &self.regular,
+                );
+            }
+        }
+    }
+}
+
+fn provenance_lines(sources: &[Provenance]) -> Vec<String> {
+    let mut lines = sources
+        .iter()
+        .map(|source| {
+            let line = match (&source.backup_uuid, &source.device) {
+                (Some(uuid), Some(device)) => format!(
+                    "Imported {} by {} from backup {} of {}",
+                    source.imported_at, source.matcher, uuid, device
+                ),
+                _ => format!(
+                    "Imported {} by {} from {}",
+                    source.imported_at, source.matcher, source.source
+                ),
+            };
+            match (&source.nickname, &source.account) {
+                (Some(nickname), Some(account)) => {
+                    format!("{}, account {} ({})", line, nickname, account)
+                }
+                _ => line,
+            }
+        })
+        .collect::<Vec<_>>();
+    if lines.is_empty() {
+        lines.push("Source unknown, imported before provenance was recorded".into());
+    }
+    lines.push(format!(
+        "Exported {} by chat_importer {}",
+        Local::now().to_rfc3339(),
+        env!("CARGO_PKG_VERSION")
+    ));
+    lines
+}
+
+/// The builtin fonts only cover latin-1.
+fn needs_font(text: &str) -> bool {
+    text.chars().any(|c| c as u32 > 0xff)
+}
+
+/// Whether any text written for the record is beyond the builtin fonts.
+fn record_needs_font(record: &StoredRecord) -> bool {
+    [record.sender(), record.content.as_str()]
+        .iter()
+        .copied()
+        .chain(record.tag("title"))
+        .chain(record.tag("url"))
+        .any(needs_font)
+        || record.blobs().iter().any(|(name, _)| needs_font(name))
+}
+
+fn message(
+    writer: &mut Writer,
+    record: &StoredRecord,
+    dir: &Path,
+    blobs: &mut BlobWriter,
+) -> Result<()> {
+    writer.advance(2.0);
+    writer.text(
+        &format!("{}  {}", record.sender(), format_time(record.timestamp)),
+        Style::Head,
+    );
+    writer.text(&record.content, Style::Body);
+    if let Some(url) = record.tag("url") {
+        if let Some(title) = record.tag("title") {
+            writer.text(title, Style::Body);
+        }
+        writer.text(url, Style::Body);
+    }
+    for blob in blobs.save(record)? {
+        if blob.media == Media::Image {
+            match read(dir.join(&blob.path)) {
+                Ok(data) if writer.image(&data) => continue,
+                Ok(_) => {}
+                Err(e) => warn!("Cannot embed {}: {}", blob.path, e),
+            }
+        }
+        writer.text(&format!("[{}] {}", blob.name, blob.path), Style::Body);
+    }
+    Ok(())
+}
+
+/// The chat as a paginated pdf named after it, images embedded and other
+/// attachments next to it, every page noting where the records were imported from.
+pub fn export_chat(
+    store: &Store,
+    chat: &ChatKey,
+    records: &[StoredRecord],
+    dir: &Path,
+    font: Option<&Path>,
+) -> Result<()> {
+    let title = display(chat);
+    let provenance = provenance_lines(&store.provenance(chat)?);
+    if font.is_none()
+        && (needs_font(&title)
+            || provenance.iter().any(|line| needs_font(line))
+            || records.iter().any(record_needs_font))
+    {
+        return Err(anyhow::anyhow!(
+            "{} has characters the builtin pdf fonts lack, pass --font with a ttf font that has them",
+            title
+        ));
+    }
+    let mut writer = Writer::new(&title, font)?;
+    writer.text(&title, Style::Title);
+    if let (Some(first), Some(last)) = (records.first(), records.last()) {
+        writer.text(
+            &format!(
+                "{} messages, {} to {}",
+                records.len(),
+                format_time(first.timestamp),
+                format_time(last.timestamp)
+            ),
+            Style::Head,
+        );
+    }
+    let mut blobs = BlobWriter::new(store, dir);
+    for record in records {
+        message(&mut writer, record, dir, &mut blobs)?;
+    }
+    writer.footers(&provenance);
+    let path = dir.join(format!("{}.pdf", chat.file_name()));
+    writer.doc.save(&mut BufWriter::new(File::create(path)?))?;
+    Ok(())
+}
+
+#[test]
+fn test_needs_font() {
+    let record = StoredRecord {
+        id: 1,
+        sender_id: "a".into(),
+        sender_name: "Zoë".into(),
+        content: "café".into(),
+        timestamp: 0,
+        metadata: None,
+        attachments: vec![],
+    };
+    assert!(!record_needs_font(&record));
+    assert!(record_needs_font(&StoredRecord {
+        sender_name: "李".into(),
+        ..record.clone()
+    }));
+    assert!(record_needs_font(&StoredRecord {
+        attachments: vec![("照片.jpg".into(), 1)],
+        ..record
+    }));
+}
+
+#[test]
+fn test_wrap() {
+    assert_eq!(wrap("a\n\nb", 10.0, 100.0), vec!["a", "", "b"]);
+    let lines = wrap(&"word ".repeat(40), 10.0, 50.0);
+    assert!(lines.len() > 1);
+    assert!(lines.iter().all(|line| text_width(line, 10.0) <= 50.0));
+    assert!(lines[..lines.len() - 1]
+        .iter()
+        .all(|line| line.ends_with("word")));
+    assert_eq!(wrap(&"字".repeat(30), 10.0, 35.3).len(), 3);
+}
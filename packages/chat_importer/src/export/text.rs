@@ -0,0 +1,181 @@
+use super::{display, format_time, BlobWriter, Media, SavedBlob};
+use crate::store::{ChatKey, Store, StoredRecord};
+use anyhow::Result;
+use serde::Serialize;
+use std::fmt::Write;
+use std::fs::{write, File};
+use std::io::BufWriter;
+use std::path::Path;
+
+fn label(media: Media) -> &'static str {
+    match media {
+        Media::Image => "image",
+        Media::Video => "video",
+        Media::Voice => "voice",
+        Media::File => "file",
+    }
+}
+
+fn txt_message(record: &StoredRecord, blobs: &[SavedBlob]) -> String {
+    let mut txt = format!("[{}] {}: ", format_time(record.timestamp), record.sender());
+    // continuation lines are indented so every message starts a line with its time
+    txt.push_str(&record.content.replace('\n', "\n    "));
+    txt.push('\n');
+    if let Some(url) = record.tag("url") {
+        let _ = writeln!(
+            txt,
+            "    {} {}",
+            record.tag("title").unwrap_or_default(),
+            url
+        );
+    }
+    for blob in blobs {
+        let _ = writeln!(
+            txt,
+            "    [{}] {} {}",
+            label(blob.media),
+            blob.name,
+            blob.path
+        );
+    }
+    txt
+}
+
+fn md_escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        if matches!(
+            c,
+            '\\' | '`' | '*' | '_' | '[' | ']' | '<' | '>' | '#' | '|' | '~'
+        ) {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn md_message(record: &StoredRecord, owner_id: &str, blobs: &[SavedBlob]) -> String {
+    let mut md = format!(
+        "**{}**{} · {}\n\n",
+        md_escape(record.sender()),
+        if record.sender_id == owner_id {
+            " (me)"
+        } else {
+            ""
+        },
+        format_time(record.timestamp)
+    );
+    let content = record
+        .content
+        .lines()
+        .map(md_escape)
+        .collect::<Vec<_>>()
+        .join("  \n");
+    if !content.is_empty() {
+        let _ = write!(md, "{}\n\n", content);
+    }
+    if let Some(url) = record.tag("url") {
+        let title = record.tag("title").unwrap_or(url);
+        let _ = write!(md, "> [{}](<{}>)", md_escape(title), url);
+        if let Some(desc) = record.tag("description") {
+            let _ = write!(md, "  \n> {}", md_escape(desc));
+        }
+        md.push_str("\n\n");
+    }
+    for blob in blobs {
+        let _ = match blob.media {
+            Media::Image => write!(md, "![{}](<{}>)\n\n", md_escape(&blob.name), blob.path),
+            media => write!(
+                md,
+                "[{}: {}](<{}>)\n\n",
+                label(media),
+                md_escape(&blob.name),
+                blob.path
+            ),
+        };
+    }
+    md
+}
+
+#[derive(Serialize)]
+struct JsonRecord<'a> {
+    #[serde(flatten)]
+    record: &'a StoredRecord,
+    time: String,
+    files: Vec<SavedBlob>,
+}
+
+#[derive(Serialize)]
+struct JsonChat<'a> {
+    #[serde(flatten)]
+    chat: &'a ChatKey,
+    records: Vec<JsonRecord<'a>>,
+}
+
+/// The chat as a single `.txt`, `.md` or `.json` file named after it.
+pub fn export_chat(
+    store: &Store,
+    chat: &ChatKey,
+    records: &[StoredRecord],
+    dir: &Path,
+    format: &str,
+) -> Result<()> {
+    let mut blobs = BlobWriter::new(store, dir);
+    let path = dir.join(format!("{}.{}", chat.file_name(), format));
+    match format {
+        "txt" => {
+            let mut txt = format!("{}\n\n", display(chat));
+            for record in records {
+                txt.push_str(&txt_message(record, &blobs.save(record)?));
+            }
+            write(path, txt)?;
+        }
+        "md" => {
+            let mut md = format!("# {}\n\n", md_escape(&display(chat)));
+            for record in records {
+                md.push_str(&md_message(record, &chat.owner_id, &blobs.save(record)?));
+                md.push_str("---\n\n");
+            }
+            write(path, md)?;
+        }
+        _ => {
+            let records = records
+                .iter()
+                .map(|record| {
+                    Ok(JsonRecord {
+                        record,
+                        time: format_time(record.timestamp),
+                        files: blobs.save(record)?,
+                    })
+                })
+                .collect::<Result<Vec<_>>>()?;
+            serde_json::to_writer_pretty(
+                BufWriter::new(File::create(path)?),
+                &JsonChat { chat, records },
+            )?;
+        }
+    }
+    Ok(())
+}
+
+#[test]
+fn test_md_message() {
+    let record = StoredRecord {
+        id: 1,
+        sender_id: "wxid_a".into(),
+        sender_name: "Alice".into(),
+        content: "see *this*\nbye".into(),
+        timestamp: 0,
+        metadata: None,
+        attachments: vec![],
+    };
+    let blobs = vec![SavedBlob {
+        name: "image".into(),
+        path: "attachments/1.jpg".into(),
+        media: Media::Image,
+    }];
+    let md = md_message(&record, "me", &blobs);
+    assert!(md.starts_with("**Alice** · "));
+    assert!(md.contains("see \\*this\\*  \nbye\n\n![image](<attachments/1.jpg>)"));
+}
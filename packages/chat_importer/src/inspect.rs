@@ -0,0 +1,166 @@
+use crate::matcher::{inspect_accounts, inspect_chats, open_backup, SmsChat, WeChatAccount};
+use anyhow::Result;
+use chrono::{Local, TimeZone};
+use ibackuptool2::{Backup, BackupReader, FileTree};
+use log::warn;
+use serde::Serialize;
+use std::path::Path;
+
+const APPS: [(&str, &str, &str); 2] = [
+    ("WeChat", "AppDomain-com.tencent.xin", "Documents/*"),
+    ("SMS", "HomeDomain", "Library/SMS/sms.db"),
+];
+
+#[derive(Serialize)]
+struct Device {
+    name: String,
+    product_type: String,
+    product_version: String,
+    build_version: Option<String>,
+    serial_number: String,
+    unique_device_id: String,
+    phone_number: Option<String>,
+    backup_date: String,
+}
+
+impl From<&Backup> for Device {
+    fn from(backup: &Backup) -> Self {
+        let lockdown = &backup.manifest.lockdown;
+        Self {
+            name: lockdown.device_name.clone(),
+            product_type: lockdown.product_type.clone(),
+            product_version: lockdown.product_version.clone(),
+            build_version: lockdown.build_version.clone(),
+            serial_number: lockdown.serial_number.clone(),
+            unique_device_id: lockdown.unique_device_id.clone(),
+            phone_number: backup.info.phone_number.clone(),
+            backup_date: backup.status.date.clone(),
+        }
+    }
+}
+
+/// What an import of the path would find.
+#[derive(Serialize)]
+struct Inspection {
+    path: String,
+    /// only itunes backups carry device details
+    device: Option<Device>,
+    encrypted: bool,
+    files: usize,
+    apps: Vec<&'static str>,
+    wechat: Vec<WeChatAccount>,
+    sms: Vec<SmsChat>,
+}
+
+impl Inspection {
+    fn new(path: &Path) -> Result<Self> {
+        let (device, encrypted, backup) = if Backup::is_backup(path) {
+            let backup = open_backup(path)?;
+            (
+                Some(Device::from(&backup)),
+                backup.manifest.is_encrypted,
+                Box::new(backup) as Box<dyn BackupReader>,
+            )
+        } else {
+            let tree = FileTree::new(path).map_err(|e| anyhow::anyhow!("{}", e))?;
+            (None, false, Box::new(tree) as Box<dyn BackupReader>)
+        };
+        let apps = APPS
+            .iter()
+            .filter(|(_, domain, pattern)| !backup.find_wildcard_paths(domain, pattern).is_empty())
+            .map(|(name, _, _)| *name)
+            .collect::<Vec<_>>();
+        Ok(Self {
+            path: path.display().to_string(),
+            device,
+            encrypted,
+            files: backup.files().len(),
+            wechat: if apps.contains(&"WeChat") {
+                inspect_accounts(backup.as_ref())
+            } else {
+                vec![]
+            },
+            sms: if apps.contains(&"SMS") {
+                inspect_chats(backup.as_ref())
+                    .map_err(|e| warn!("Failed to inspect sms: {}", e))
+                    .unwrap_or_default()
+            } else {
+                vec![]
+            },
+            apps,
+        })
+    }
+
+    fn print(&self) {
+        let time = |ms: i64| {
+            Local
+                .timestamp_millis_opt(ms)
+                .single()
+                .map(|t| t.format("%Y-%m-%d %H:%M").to_string())
+                .unwrap_or_default()
+        };
+        println!("{}", self.path);
+        if let Some(device) = &self.device {
+            println!(
+                "  device:    {} ({}, iOS {} {})",
+                device.name,
+                device.product_type,
+                device.product_version,
+                device.build_version.as_deref().unwrap_or_default()
+            );
+            println!("  udid:      {}", device.unique_device_id);
+            println!("  serial:    {}", device.serial_number);
+            if let Some(phone) = &device.phone_number {
+                println!("  phone:     {}", phone);
+            }
+            println!("  backup at: {}", device.backup_date);
+        }
+        println!("  encrypted: {}", self.encrypted);
+        println!("  files:     {}", self.files);
+        println!("  apps:      {}", self.apps.join(", "));
+        for account in self.wechat.iter() {
+            println!(
+                "  wechat {}: {} ({}), {} chats, {} messages{}",
+                account.account,
+                account.wxid,
+                account.nickname,
+                account.chats,
+                account.messages,
+                if account.missing.is_empty() {
+                    String::new()
+                } else {
+                    format!(", missing {} db", account.missing.join("/"))
+                }
+            );
+        }
+        if !self.sms.is_empty() {
+            println!(
+                "  sms: {} chats, {} messages",
+                self.sms.len(),
+                self.sms.iter().map(|c| c.messages).sum::<i64>()
+            );
+            for chat in self.sms.iter() {
+                println!(
+                    "    {}: {} messages, {} - {}",
+                    chat.chat,
+                    chat.messages,
+                    time(chat.first),
+                    time(chat.last)
+                );
+            }
+        }
+    }
+}
+
+pub fn inspect<P: AsRef<Path>>(paths: &[P], json: bool) -> Result<()> {
+    let inspections = paths
+        .iter()
+        .map(|path| Inspection::new(path.as_ref()))
+        .collect::<Result<Vec<_>>>()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&inspections)?);
+    } else {
+        inspections.iter().for_each(Inspection::print);
+    }
+    Ok(())
+}
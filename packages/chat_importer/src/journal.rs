@@ -0,0 +1,247 @@
+use crate::matcher::{Account, Progress};
+use anyhow::Result;
+use chrono::Local;
+use gchdb::Record;
+use ibackuptool2::Backup;
+use rusqlite::{params, Connection, OptionalExtension};
+use std::cell::RefCell;
+use std::path::Path;
+use std::time::Duration;
+
+/// Import progress of every source, next to the records in `record.db`.
+///
+/// Matchers that can resume yield the records of a chat in time order, so the
+/// newest committed timestamp of each chat is where an interrupted import continues,
+/// the others import unfinished sources again.
+pub struct Journal {
+    conn: Connection,
+    resume: bool,
+    /// (matcher, source) being imported
+    current: RefCell<Option<(String, String)>>,
+    /// committed since the last checkpoint
+    pending: RefCell<Progress>,
+}
+
+impl Journal {
+    pub fn open<P: AsRef<Path>>(path: P, resume: bool) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS import_sources (
+                matcher TEXT NOT NULL,
+                source TEXT NOT NULL,
+                finished INTEGER NOT NULL DEFAULT 0,
+                PRIMARY KEY (matcher, source)
+            );
+            CREATE TABLE IF NOT EXISTS import_journal (
+                matcher TEXT NOT NULL,
+                source TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                chat_id TEXT NOT NULL,
+                last_timestamp INTEGER NOT NULL,
+                PRIMARY KEY (matcher, source, owner_id, chat_id)
+            );
+            CREATE TABLE IF NOT EXISTS import_provenance (
+                matcher TEXT NOT NULL,
+                source TEXT NOT NULL,
+                backup_uuid TEXT,
+                device TEXT,
+                imported_at TEXT NOT NULL,
+                PRIMARY KEY (matcher, source)
+            );
+            CREATE TABLE IF NOT EXISTS import_accounts (
+                matcher TEXT NOT NULL,
+                source TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                account TEXT NOT NULL,
+                nickname TEXT NOT NULL,
+                PRIMARY KEY (matcher, source, owner_id)
+            );",
+        )?;
+        Ok(Self {
+            conn,
+            resume,
+            current: RefCell::new(None),
+            pending: RefCell::new(Progress::new()),
+        })
+    }
+
+    /// Start importing a source, returns the committed progress of an earlier run,
+    /// or `None` when `--resume` finds it already finished.
+    pub fn begin(&self, matcher: &str, source: &Path) -> Result<Option<Progress>> {
+        let source = source.to_string_lossy().to_string();
+        let finished: Option<bool> = self
+            .conn
+            .query_row(
+                "SELECT finished FROM import_sources WHERE matcher = ?1 AND source = ?2",
+                params![matcher, source],
+                |row| row.get(0),
+            )
+            .optional()?;
+        if self.resume && finished == Some(true) {
+            return Ok(None);
+        }
+        let progress = if self.resume {
+            let mut stmt = self.conn.prepare(
+                "SELECT owner_id, chat_id, last_timestamp FROM import_journal
+                WHERE matcher = ?1 AND source = ?2",
+            )?;
+            let rows = stmt.query_map(params![matcher, source], |row| {
+                Ok(((row.get(0)?, row.get(1)?), row.get(2)?))
+            })?;
+            rows.collect::<Result<Progress, _>>()?
+        } else {
+            self.conn.execute(
+                "DELETE FROM import_journal WHERE matcher = ?1 AND source = ?2",
+                params![matcher, source],
+            )?;
+            Progress::new()
+        };
+        self.conn.execute(
+            "INSERT INTO import_sources (matcher, source) VALUES (?1, ?2)
+            ON CONFLICT (matcher, source) DO UPDATE SET finished = 0",
+            params![matcher, source],
+        )?;
+        self.current.replace(Some((matcher.into(), source)));
+        self.pending.replace(Progress::new());
+        Ok(Some(progress))
+    }
+
+    /// Record where the current source was imported from, `backup` is the
+    /// itunes backup its matcher opened and `accounts` the app accounts in it.
+    pub fn provenance(&self, backup: Option<&Backup>, accounts: &[Account]) -> Result<()> {
+        let current = self.current.borrow();
+        let (matcher, source) = match current.as_ref() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let (backup_uuid, device) = backup.map(backup_provenance).unzip();
+        self.conn.execute(
+            "INSERT INTO import_provenance (matcher, source, backup_uuid, device, imported_at)
+            VALUES (?1, ?2, ?3, ?4, ?5)
+            ON CONFLICT (matcher, source) DO UPDATE SET backup_uuid = excluded.backup_uuid,
+            device = excluded.device, imported_at = excluded.imported_at",
+            params![
+                matcher,
+                source,
+                backup_uuid,
+                device,
+                Local::now().to_rfc3339()
+            ],
+        )?;
+        self.conn.execute(
+            "DELETE FROM import_accounts WHERE matcher = ?1 AND source = ?2",
+            params![matcher, source],
+        )?;
+        for account in accounts {
+            self.conn.execute(
+                "INSERT INTO import_accounts (matcher, source, owner_id, account, nickname)
+                VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    matcher,
+                    source,
+                    account.owner_id,
+                    account.account,
+                    account.nickname
+                ],
+            )?;
+        }
+        Ok(())
+    }
+
+    pub fn committed(&self, record: &Record) {
+        let mut pending = self.pending.borrow_mut();
+        let last = pending
+            .entry((record.owner_id.clone(), record.group_id.clone()))
+            .or_insert(record.timestamp);
+        *last = (*last).max(record.timestamp);
+    }
+
+    /// Persist the progress of records committed since the last checkpoint.
+    pub fn checkpoint(&self) -> Result<()> {
+        let current = self.current.borrow();
+        let (matcher, source) = match current.as_ref() {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let tx = self.conn.unchecked_transaction()?;
+        for ((owner_id, chat_id), timestamp) in self.pending.borrow_mut().drain() {
+            tx.execute(
+                "INSERT INTO import_journal (matcher, source, owner_id, chat_id, last_timestamp)
+                VALUES (?1, ?2, ?3, ?4, ?5)
+                ON CONFLICT (matcher, source, owner_id, chat_id) DO UPDATE SET
+                last_timestamp = max(last_timestamp, excluded.last_timestamp)",
+                params![matcher, source, owner_id, chat_id, timestamp],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    pub fn finish(&self) -> Result<()> {
+        self.checkpoint()?;
+        if let Some((matcher, source)) = self.current.replace(None) {
+            self.conn.execute(
+                "UPDATE import_sources SET finished = 1 WHERE matcher = ?1 AND source = ?2",
+                params![matcher, source],
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Backup UUID and device of an itunes backup, kept for the provenance of exports.
+fn backup_provenance(backup: &Backup) -> (String, String) {
+    let lockdown = &backup.manifest.lockdown;
+    (
+        backup.status.uuid.clone(),
+        format!(
+            "{} ({}, iOS {}, {})",
+            lockdown.device_name,
+            lockdown.product_type,
+            lockdown.product_version,
+            lockdown.unique_device_id
+        ),
+    )
+}
+
+#[test]
+fn test_journal() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("record.db");
+    let record = |timestamp| Record {
+        owner_id: "me".into(),
+        group_id: "chat".into(),
+        timestamp,
+        ..Default::default()
+    };
+    let journal = Journal::open(&path, false).unwrap();
+    assert_eq!(
+        journal.begin("SMS", Path::new("a")).unwrap(),
+        Some(Progress::new())
+    );
+    let account = Account {
+        owner_id: "me".into(),
+        account: "hash".into(),
+        nickname: "Me".into(),
+    };
+    journal.provenance(None, &[account]).unwrap();
+    let nickname: String = journal
+        .conn
+        .query_row(
+            "SELECT nickname FROM import_accounts WHERE owner_id = 'me'",
+            params![],
+            |row| row.get(0),
+        )
+        .unwrap();
+    assert_eq!(nickname, "Me");
+    journal.committed(&record(2));
+    journal.committed(&record(1));
+    journal.checkpoint().unwrap();
+    // interrupted here, resumed by the next run
+    let journal = Journal::open(&path, true).unwrap();
+    let progress = journal.begin("SMS", Path::new("a")).unwrap().unwrap();
+    assert_eq!(progress.get(&("me".into(), "chat".into())), Some(&2));
+    journal.finish().unwrap();
+    assert_eq!(journal.begin("SMS", Path::new("a")).unwrap(), None);
+}
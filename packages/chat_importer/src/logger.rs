@@ -1,10 +1,34 @@
+use crate::progress_bar::eprintln;
+use anyhow::Result;
 use fern::Dispatch;
 use log::{LevelFilter, Log, Metadata, Record};
+use std::path::Path;
 
-pub fn init_logger(level: LevelFilter) -> Result<(), log::SetLoggerError> {
-    Dispatch::new()
+/// Logs go to stderr, and are appended to `file` too when given.
+pub fn init_logger(level: LevelFilter, json: bool, file: Option<&Path>) -> Result<()> {
+    let mut dispatch = Dispatch::new()
         .level(level)
-        .format(move |out, message, record| {
+        .chain(format(json).chain(Box::new(Logger {}) as Box<dyn Log>));
+    if let Some(file) = file {
+        dispatch = dispatch.chain(format(json).chain(fern::log_file(file)?));
+    }
+    dispatch.apply()?;
+    Ok(())
+}
+
+fn format(json: bool) -> Dispatch {
+    Dispatch::new().format(move |out, message, record| {
+        if json {
+            out.finish(format_args!(
+                "{}",
+                serde_json::json!({
+                    "time": chrono::Local::now().to_rfc3339(),
+                    "level": record.level().as_str(),
+                    "target": record.target(),
+                    "message": message.to_string(),
+                })
+            ))
+        } else {
             out.finish(format_args!(
                 "{}[{:>5}][{}] {}",
                 chrono::Local::now().format("[%H:%M:%S]"),
@@ -12,9 +36,8 @@ pub fn init_logger(level: LevelFilter) -> Result<(), log::SetLoggerError> {
                 record.target(),
                 message
             ))
-        })
-        .chain(Box::new(Logger {}) as Box<dyn Log>)
-        .apply()
+        }
+    })
 }
 
 struct Logger;
@@ -26,7 +49,7 @@ impl Log for Logger {
 
     fn log(&self, record: &Record) {
         if self.enabled(record.metadata()) {
-            println!("{}", record.args());
+            eprintln(&record.args().to_string());
         }
     }
 
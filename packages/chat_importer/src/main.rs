@@ -1,37 +1,288 @@
 mod args;
+mod detect;
+mod dry_run;
+mod export;
+mod inspect;
+mod journal;
 mod logger;
 mod matcher;
+mod progress_bar;
+mod search;
+mod snapshot;
+mod store;
+mod summary;
 
-use anyhow::Result;
-use args::{get_cmd, get_log_level, get_paths, SubCommand};
+use anyhow::{Context, Result};
+use args::{
+    day_bounds, get_batch_size, get_cmd, get_date_range, get_db, get_dry_run, get_jobs,
+    get_log_file, get_log_json, get_log_level, get_password_env, get_paths, get_resume,
+    get_summary, SubCommand,
+};
+use detect::detect_inputs;
+use dry_run::DryRun;
+use export::{export, ChatExportOptions};
 use gchdb::SqliteChatRecorder;
+use inspect::inspect;
+use journal::Journal;
 use logger::init_logger;
-use matcher::{exporter, info, ExportType};
+use matcher::{
+    error, exporter, info, list_chats, set_backup_password, ChatFilter, DateRange, ExportOptions,
+    ExportType, IosSource, TextLogProfile,
+};
+use path_ext::PathExt;
+use regex::Regex;
+use search::{search, SearchQuery};
+use snapshot::{Snapshot, SnapshotLog};
+use std::path::PathBuf;
+use store::{ChatQuery, Search};
+use summary::Summary;
 
 fn main() -> Result<()> {
-    init_logger(get_log_level().to_level_filter())?;
-    let mut recorder = SqliteChatRecorder::new("record.db")?;
-    for path in get_paths() {
+    init_logger(
+        get_log_level().to_level_filter(),
+        get_log_json(),
+        get_log_file().as_deref(),
+    )?;
+    if let Some(var) = get_password_env() {
+        set_backup_password(
+            std::env::var(&var).with_context(|| format!("Cannot read backup password: {}", var))?,
+        );
+    }
+    rayon::ThreadPoolBuilder::new()
+        .num_threads(get_jobs())
+        .build_global()?;
+    match get_cmd() {
+        SubCommand::Inspect { format, .. } => return inspect(&get_paths(), format == "json"),
+        SubCommand::Export {
+            format,
+            chat_type,
+            owner_id,
+            group_id,
+            out,
+            page_size,
+            font,
+        } => {
+            return export(
+                &get_db(),
+                format,
+                &ChatExportOptions {
+                    query: ChatQuery {
+                        chat_type: chat_type.clone(),
+                        owner_id: owner_id.clone(),
+                        group_id: group_id.clone(),
+                    },
+                    range: DateRange::new(get_date_range()),
+                    out: out.clone(),
+                    page_size: *page_size,
+                    font: font.clone(),
+                },
+            )
+        }
+        SubCommand::Search {
+            query,
+            chat_type,
+            owner_id,
+            group_id,
+            sender,
+            has_attachment,
+            context,
+            limit,
+            format,
+        } => {
+            return search(
+                &get_db(),
+                &Search {
+                    query: SearchQuery::parse(query)?,
+                    chat: ChatQuery {
+                        chat_type: chat_type.clone(),
+                        owner_id: owner_id.clone(),
+                        group_id: group_id.clone(),
+                    },
+                    sender: sender.clone(),
+                    range: DateRange::new(get_date_range()),
+                    has_attachment: *has_attachment,
+                    limit: *limit,
+                },
+                *context,
+                format == "json",
+            )
+        }
+        SubCommand::WeChat { list: true, .. } => {
+            let filter = chat_filter(get_cmd())?;
+            for path in get_paths() {
+                list_chats(path, &filter)?;
+            }
+            return Ok(());
+        }
+        _ => {}
+    }
+    let db = get_db();
+    let mut recorder = SqliteChatRecorder::new(db.to_string_lossy().as_ref())?;
+    let snapshots = matches!(
+        get_cmd(),
+        SubCommand::WeChat {
+            snapshots: true,
+            ..
+        } | SubCommand::SMS {
+            snapshots: true,
+            ..
+        }
+    );
+    let dry_run = get_dry_run()
+        .is_some()
+        .then(|| DryRun::open(&db))
+        .transpose()?;
+    let journal = dry_run
+        .is_none()
+        .then(|| Journal::open(&db, get_resume()))
+        .transpose()?;
+    let summary = dry_run.is_none().then(|| Summary::open(&db)).transpose()?;
+    // a dry run leaves the snapshot tables alone too
+    let mut snapshot_log = (snapshots && dry_run.is_none())
+        .then(|| SnapshotLog::open(&db))
+        .transpose()?;
+    // snapshots are imported oldest first, others in the given order
+    let paths: Vec<(PathBuf, Option<Snapshot>)> = if snapshots {
+        Snapshot::sort(get_paths())?
+            .into_iter()
+            .map(|s| (s.path.clone(), Some(s)))
+            .collect()
+    } else {
+        get_paths().into_iter().map(|p| (p, None)).collect()
+    };
+    for (path, snapshot) in paths {
         info!("Processing: {}", path.display());
-        exporter(
-            &mut recorder,
-            match get_cmd() {
-                SubCommand::QQ { owner, .. } => ExportType::WindowsQQ(path, owner.into()),
-                SubCommand::WeChat { chat_names, .. } => ExportType::iOSWeChat(
-                    path,
-                    chat_names.as_ref().map(|names| {
-                        (!names.is_empty())
-                            .then_some(names.split(',').map(|s| s.into()).collect())
-                            .unwrap_or_default()
-                    }),
-                ),
-                SubCommand::SMS { owner, .. } => ExportType::iOSSMS(path, owner.into()),
-            },
-        )?;
+        if let (Some(log), Some(snapshot)) = (snapshot_log.as_mut(), &snapshot) {
+            log.begin(snapshot)?;
+        }
+        let options = ExportOptions {
+            snapshots: snapshot_log.as_ref(),
+            range: DateRange::new(get_date_range()),
+            dry_run: dry_run.as_ref(),
+            batch_size: get_batch_size(),
+            journal: journal.as_ref(),
+            summary: summary.as_ref(),
+        };
+        match get_cmd() {
+            // one unreadable archive doesn't stop the others
+            SubCommand::Auto { owner, .. } => {
+                for export_type in detect_inputs(&path, owner) {
+                    let source = export_type.source().1.to_path_buf();
+                    if let Err(e) = exporter(&mut recorder, export_type, &options) {
+                        error!("Failed to import {}: {:#}", source.display(), e);
+                    }
+                }
+            }
+            cmd => exporter(&mut recorder, export_type(cmd, path)?, &options)?,
+        }
+    }
+    if let Some(log) = snapshot_log {
+        log.report()?;
+    }
+    if let Some(summary) = summary {
+        summary.report(get_summary())?;
+    }
+    if let Some(dry_run) = dry_run {
+        dry_run.report(get_dry_run().flatten())?;
     }
     Ok(())
 }
 
+/// The input of an import subcommand.
+fn export_type(cmd: &SubCommand, path: PathBuf) -> Result<ExportType<PathBuf>> {
+    Ok(match cmd {
+        SubCommand::QQ { owner, .. } => ExportType::WindowsQQ(path, owner.into()),
+        SubCommand::WeChat { chat_names, .. } => ExportType::iOSWeChat(
+            IosSource::new(path),
+            chat_names.as_ref().map(|names| {
+                if names.is_empty() {
+                    vec![]
+                } else {
+                    names.split(',').map(|s| s.into()).collect()
+                }
+            }),
+            chat_filter(cmd)?,
+        ),
+        SubCommand::SMS { owner, .. } => ExportType::iOSSMS(IosSource::new(path), owner.into()),
+        SubCommand::Slack { workspace, .. } => ExportType::SlackExport(path, workspace.clone()),
+        SubCommand::Mail { owner, .. }
+            if path.is_dir() || path.ext_str().eq_ignore_ascii_case("eml") =>
+        {
+            ExportType::Eml(path, owner.into())
+        }
+        SubCommand::Mail { owner, .. } => ExportType::Mbox(path, owner.into()),
+        SubCommand::TextLog {
+            profile,
+            owner,
+            chat_id,
+            timezone,
+            ..
+        } => {
+            let profile = TextLogProfile::load(profile)?;
+            ExportType::TextLog(
+                path,
+                TextLogProfile {
+                    chat_id: chat_id.clone().or(profile.chat_id),
+                    timezone: timezone.clone().or(profile.timezone),
+                    ..profile
+                },
+                owner.into(),
+            )
+        }
+        // profiles are expanded while parsing the command line
+        SubCommand::Run { profile, .. } => {
+            return Err(anyhow::anyhow!("Run profile {} was not expanded", profile))
+        }
+        SubCommand::Inspect { .. }
+        | SubCommand::Auto { .. }
+        | SubCommand::Export { .. }
+        | SubCommand::Search { .. } => return Err(anyhow::anyhow!("Not an import subcommand")),
+    })
+}
+
+fn chat_filter(cmd: &SubCommand) -> Result<ChatFilter> {
+    Ok(match cmd {
+        SubCommand::WeChat {
+            accounts,
+            pattern,
+            groups,
+            private,
+            active_since,
+            active_until,
+            min_messages,
+            include,
+            exclude,
+            ..
+        } => ChatFilter {
+            accounts: accounts
+                .as_ref()
+                .map(|a| a.split(',').map(|s| s.into()).collect())
+                .unwrap_or_default(),
+            pattern: pattern.as_deref().map(Regex::new).transpose()?,
+            groups: (*groups || *private).then_some(*groups),
+            active_since: active_since.as_ref().map(|d| day_bounds(d).0 / 1000),
+            active_until: active_until.as_ref().map(|d| day_bounds(d).1 / 1000),
+            min_messages: *min_messages,
+            include: include.as_ref().map(ChatFilter::load_list).transpose()?,
+            exclude: exclude
+                .as_ref()
+                .map(ChatFilter::load_list)
+                .transpose()?
+                .unwrap_or_default(),
+        },
+        _ => ChatFilter::default(),
+    })
+}
+
+#[test]
+fn test_export_type() {
+    let run = SubCommand::Run {
+        profile: "family".into(),
+        args: vec![],
+    };
+    assert!(export_type(&run, PathBuf::from("backup")).is_err());
+}
+
 #[test]
 fn test_load_blobs() {
     use rusqlite::{Connection, OpenFlags};
@@ -1,7 +1,8 @@
 use super::*;
 use chrono::{Duration, TimeZone, Utc};
-use ibackuptool2::Backup;
+use ibackuptool2::{BackupReader, FileTree};
 use rusqlite::{params, Connection, OpenFlags, Result as SqliteResult};
+use serde::Serialize;
 use std::io::Write;
 use tempfile::NamedTempFile;
 
@@ -18,10 +19,27 @@ struct RecordLine {
     is_spam: bool,
 }
 
+// message.date is in nanoseconds since 2001-01-01, or in seconds before iOS 11
+const LEGACY_DATE_LIMIT: i64 = 100_000_000_000;
+const MESSAGE_DATE: &str = "(CASE WHEN message.date < 100000000000
+    THEN message.date * 1000000000 ELSE message.date END)";
+
+fn date_to_ms(date: i64) -> i64 {
+    let base_date_offset = Utc.timestamp_opt(978307200, 0).unwrap();
+    let elapsed = if date < LEGACY_DATE_LIMIT {
+        Duration::seconds(date)
+    } else {
+        Duration::nanoseconds(date)
+    };
+    (base_date_offset + elapsed).timestamp_millis()
+}
+
 #[allow(non_camel_case_types)]
 struct Extractor {
     conn: Connection,
     owner: String,
+    range: DateRange,
+    progress: Progress,
 }
 
 impl Extractor {
@@ -34,9 +52,15 @@ impl Extractor {
                     | OpenFlags::SQLITE_OPEN_NO_MUTEX,
             )?,
             owner,
+            range: DateRange::default(),
+            progress: Progress::new(),
         })
     }
 
+    pub fn with_range(self, range: DateRange) -> Self {
+        Self { range, ..self }
+    }
+
     fn get_chat_ids(&self) -> SqliteResult<Vec<i32>> {
         Ok(self
             .conn
@@ -56,8 +80,33 @@ impl Extractor {
             == Some(1))
     }
 
+    /// The oldest committed record of the conversations in a chat, `None`
+    /// while one of them has none and the chat is read from its start.
+    fn resume_point(&self, chat_id: i32) -> SqliteResult<Option<i64>> {
+        if self.progress.is_empty() {
+            return Ok(None);
+        }
+        let keys = self
+            .conn
+            .prepare(
+                "SELECT DISTINCT message.destination_caller_id, handle.id
+                FROM chat_message_join
+                INNER JOIN message
+                    ON message.rowid = chat_message_join.message_id
+                INNER JOIN handle
+                    ON handle.rowid = message.handle_id
+                WHERE chat_message_join.chat_id = ?1",
+            )?
+            .query_map(params![chat_id], |row| Ok((row.get(0)?, row.get(1)?)))?
+            .collect::<SqliteResult<Vec<(String, String)>>>()?;
+        Ok(keys
+            .iter()
+            .map(|key| self.progress.get(key).copied())
+            .collect::<Option<Vec<_>>>()
+            .and_then(|points| points.into_iter().min()))
+    }
+
     fn get_record_lines(&self, chat_id: i32) -> SqliteResult<Vec<Record>> {
-        let base_date_offset = Utc.timestamp(978307200, 0);
         let has_is_spam = self.check_has_is_spam()?;
         let mut stmt = self.conn.prepare(&format!(
             "SELECT
@@ -66,7 +115,7 @@ impl Extractor {
                 message.text,
                 message.handle_id,
                 message.service,
-                message.date,
+                {date},
                 message.is_from_me,
                 message.destination_caller_id,
                 {}
@@ -75,23 +124,29 @@ impl Extractor {
                 ON message.rowid = chat_message_join.message_id
             INNER JOIN handle
                 ON handle.rowid = message.handle_id
-            WHERE chat_message_join.chat_id = ?
-            ORDER by date asc",
-            if has_is_spam { "message.is_spam" } else { "0" }
+            WHERE chat_message_join.chat_id = ?1
+                AND {date} >= ?2 AND {date} <= ?3
+            ORDER by {date} asc",
+            if has_is_spam { "message.is_spam" } else { "0" },
+            date = MESSAGE_DATE
         ))?;
-        let records_iter = stmt.query_map(params![chat_id], |row| {
-            Ok(RecordLine {
-                id: row.get(0)?,
-                target: row.get(1)?,
-                text: row.get(2)?,
-                handle_id: row.get(3)?,
-                service: row.get(4)?,
-                date: row.get(5)?,
-                is_from_me: row.get(6)?,
-                destination_caller_id: row.get(7)?,
-                is_spam: row.get(8)?,
-            })
-        })?;
+        let to_date = |ms: i64| ms.saturating_sub(978307200000).saturating_mul(1_000_000);
+        let range = self.range.resume_from(self.resume_point(chat_id)?.as_ref());
+        let (since, until) = range.bounds();
+        let records_iter =
+            stmt.query_map(params![chat_id, to_date(since), to_date(until)], |row| {
+                Ok(RecordLine {
+                    id: row.get(0)?,
+                    target: row.get(1)?,
+                    text: row.get(2)?,
+                    handle_id: row.get(3)?,
+                    service: row.get(4)?,
+                    date: row.get(5)?,
+                    is_from_me: row.get(6)?,
+                    destination_caller_id: row.get(7)?,
+                    is_spam: row.get(8)?,
+                })
+            })?;
 
         Ok(records_iter
             .filter_map(|r| r.ok())
@@ -110,27 +165,29 @@ impl Extractor {
                     record.target
                 },
                 content: record.text,
-                timestamp: (base_date_offset + Duration::nanoseconds(record.date))
-                    .timestamp_millis(),
+                timestamp: date_to_ms(record.date),
                 ..Default::default()
             })
+            // conversations of the chat committed further than the others
+            .filter(|record| {
+                self.progress
+                    .get(&(record.owner_id.clone(), record.group_id.clone()))
+                    .is_none_or(|last| record.timestamp >= *last)
+            })
             .collect())
     }
 }
 
 impl MsgMatcher for Extractor {
-    fn get_records(&self) -> Option<Vec<RecordType>> {
+    fn get_records(&self) -> Option<Records<'_>> {
         self.get_chat_ids()
             .map(|ids| {
-                ids.iter()
-                    .filter_map(|id| {
-                        self.get_record_lines(*id)
-                            .map_err(|e| warn!("Failed to get sms record {}: {}", id, e))
-                            .ok()
-                    })
-                    .flatten()
-                    .map(RecordType::from)
-                    .collect()
+                Box::new(ids.into_iter().flat_map(move |id| {
+                    records_of(
+                        self.get_record_lines(id)
+                            .with_context(|| format!("Failed to get sms record {}", id)),
+                    )
+                })) as Records<'_>
             })
             .map_err(|e| warn!("Failed to get chat ids: {}", e))
             .ok()
@@ -144,35 +201,94 @@ pub struct Matcher {
 }
 
 impl Matcher {
-    pub fn new<P: AsRef<Path>>(path: P, owner: String) -> Result<Box<dyn MsgMatcher>> {
-        let backup = Self::init_backup(path).map_err(|e| anyhow::anyhow!("{}", e))?;
-        if let Some(sms) = backup.find_path("HomeDomain", "Library/SMS/sms.db") {
-            let mut tempfile = NamedTempFile::new()?;
-            tempfile.write_all(
-                &backup
-                    .read_file(&sms)
-                    .map_err(|e| anyhow::anyhow!("{}", e))?,
-            )?;
-            Ok(Box::new(Self {
-                extractor: Extractor::new(tempfile.path(), owner)?,
-                _smsdb: tempfile,
-            }) as Box<dyn MsgMatcher>)
+    pub fn new(source: &IosSource, owner: String, range: DateRange) -> Result<Box<dyn MsgMatcher>> {
+        let backup = Self::init_backup(source).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let smsdb = extract_db(backup.as_ref())?;
+        Ok(Box::new(Self {
+            extractor: Extractor::new(smsdb.path(), owner)?.with_range(range),
+            _smsdb: smsdb,
+        }) as Box<dyn MsgMatcher>)
+    }
+
+    fn init_backup(
+        source: &IosSource,
+    ) -> Result<Arc<dyn BackupReader>, Box<dyn std::error::Error>> {
+        if source.is_backup() {
+            Ok(source.backup()?)
         } else {
-            Err(anyhow::anyhow!("Failed to find sms database"))
+            let path = source.path();
+            // extracted home folder or full filesystem dump
+            let tree = FileTree::new(path)?;
+            if tree.find_path("HomeDomain", "Library/SMS/sms.db").is_some() {
+                Ok(Arc::new(tree))
+            } else {
+                Ok(Arc::new(FileTree::with_domain(path, "HomeDomain")?))
+            }
         }
     }
+}
 
-    fn init_backup<P: AsRef<Path>>(path: P) -> Result<Backup, Box<dyn std::error::Error>> {
-        let mut backup = Backup::new(path)?;
-        backup.parse_manifest()?;
-        Ok(backup)
+fn extract_db(backup: &dyn BackupReader) -> Result<NamedTempFile> {
+    if let Some(sms) = backup.find_path("HomeDomain", "Library/SMS/sms.db") {
+        let mut tempfile = NamedTempFile::new()?;
+        tempfile.write_all(
+            &backup
+                .read_file(&sms)
+                .map_err(|e| anyhow::anyhow!("{}", e))?,
+        )?;
+        Ok(tempfile)
+    } else {
+        Err(anyhow::anyhow!("Failed to find sms database"))
     }
 }
 
+/// Message count and time range (ms) of an sms conversation.
+#[derive(Serialize)]
+pub struct SmsChat {
+    pub chat: String,
+    pub messages: i64,
+    pub first: i64,
+    pub last: i64,
+}
+
+/// Conversations in the sms database, grouped like imported records.
+pub fn inspect_chats(backup: &dyn BackupReader) -> Result<Vec<SmsChat>> {
+    let smsdb = extract_db(backup)?;
+    let extractor = Extractor::new(smsdb.path(), "".into())?;
+    let chats = extractor
+        .conn
+        .prepare(&format!(
+            "SELECT handle.id, count(*), min({date}), max({date})
+            FROM chat_message_join
+            INNER JOIN message
+                ON message.rowid = chat_message_join.message_id
+            INNER JOIN handle
+                ON handle.rowid = message.handle_id
+            GROUP BY handle.id
+            ORDER BY handle.id",
+            date = MESSAGE_DATE
+        ))?
+        .query_map(params![], |row| {
+            Ok(SmsChat {
+                chat: row.get(0)?,
+                messages: row.get(1)?,
+                first: date_to_ms(row.get(2)?),
+                last: date_to_ms(row.get(3)?),
+            })
+        })?
+        .collect::<SqliteResult<Vec<_>>>()?;
+    Ok(chats)
+}
+
 impl MsgMatcher for Matcher {
-    fn get_records(&self) -> Option<Vec<RecordType>> {
+    fn get_records(&self) -> Option<Records<'_>> {
         self.extractor.get_records()
     }
+
+    fn resume(&mut self, progress: Progress) -> bool {
+        self.extractor.progress = progress;
+        true
+    }
 }
 
 #[test]
@@ -192,3 +308,74 @@ fn test_ios_sms_db() -> SqliteResult<()> {
     }
     Ok(())
 }
+
+#[test]
+fn test_ios_sms_legacy_date() -> SqliteResult<()> {
+    let db = NamedTempFile::new().unwrap();
+    Connection::open(db.path())?.execute_batch(
+        "CREATE TABLE handle (ROWID INTEGER PRIMARY KEY, id TEXT);
+        CREATE TABLE message (ROWID INTEGER PRIMARY KEY, text TEXT, handle_id INTEGER,
+            service TEXT, date INTEGER, is_from_me INTEGER, destination_caller_id TEXT);
+        CREATE TABLE chat_message_join (chat_id INTEGER, message_id INTEGER);
+        INSERT INTO handle VALUES (1, '+10000');
+        -- 2016-01-01 00:00:00 UTC in seconds, as written before iOS 11
+        INSERT INTO message VALUES (1, 'legacy', 1, 'SMS', 473299200, 0, 'me');
+        -- 2018-01-01 00:00:00 UTC in nanoseconds
+        INSERT INTO message VALUES (2, 'modern', 1, 'SMS', 536457600000000000, 0, 'me');
+        INSERT INTO chat_message_join VALUES (1, 1), (1, 2);",
+    )?;
+
+    assert_eq!(date_to_ms(473299200), 1451606400000);
+    assert_eq!(date_to_ms(536457600000000000), 1514764800000);
+
+    let extractor = Extractor::new(db.path(), "".into())?;
+    let records = extractor.get_record_lines(1)?;
+    assert_eq!(
+        records.iter().map(|r| r.timestamp).collect::<Vec<_>>(),
+        vec![1451606400000, 1514764800000]
+    );
+
+    let range = DateRange::new((Some(1420070400000), Some(1483228800000)));
+    let records = extractor.with_range(range).get_record_lines(1)?;
+    assert_eq!(records.len(), 1);
+    assert_eq!(records[0].content, "legacy");
+    Ok(())
+}
+
+#[test]
+fn test_ios_sms_resume() -> SqliteResult<()> {
+    let db = NamedTempFile::new().unwrap();
+    Connection::open(db.path())?.execute_batch(
+        "CREATE TABLE handle (ROWID INTEGER PRIMARY KEY, id TEXT);
+        CREATE TABLE message (ROWID INTEGER PRIMARY KEY, text TEXT, handle_id INTEGER,
+            service TEXT, date INTEGER, is_from_me INTEGER, destination_caller_id TEXT);
+        CREATE TABLE chat_message_join (chat_id INTEGER, message_id INTEGER);
+        INSERT INTO handle VALUES (1, '+10000'), (2, '+20000');
+        -- 2018-01-01 00:00:00, 00:01:00 and 00:02:00 UTC
+        INSERT INTO message VALUES (1, 'first', 1, 'SMS', 536457600000000000, 0, 'me');
+        INSERT INTO message VALUES (2, 'second', 1, 'SMS', 536457660000000000, 0, 'me');
+        INSERT INTO message VALUES (3, 'third', 1, 'SMS', 536457720000000000, 0, 'me');
+        INSERT INTO message VALUES (4, 'other', 2, 'SMS', 536457600000000000, 0, 'me');
+        INSERT INTO chat_message_join VALUES (1, 1), (1, 2), (1, 3), (2, 1), (2, 2), (2, 4);",
+    )?;
+    let contents =
+        |records: Vec<Record>| records.into_iter().map(|r| r.content).collect::<Vec<_>>();
+
+    let mut extractor = Extractor::new(db.path(), "".into())?;
+    extractor
+        .progress
+        .insert(("me".into(), "+10000".into()), 1514764860000);
+    assert_eq!(extractor.resume_point(1)?, Some(1514764860000));
+    // the record of the last committed timestamp is imported again
+    assert_eq!(
+        contents(extractor.get_record_lines(1)?),
+        ["second", "third"]
+    );
+    // nothing committed of +20000 yet, the chat is read from its start
+    assert_eq!(extractor.resume_point(2)?, None);
+    assert_eq!(
+        contents(extractor.get_record_lines(2)?),
+        ["other", "second"]
+    );
+    Ok(())
+}
@@ -1,20 +1,25 @@
 use super::*;
+use crate::summary::InChat;
 use binread::*;
-use ibackuptool2::{Backup, BackupFile};
+use chrono::TimeZone;
+use ibackuptool2::{BackupFile, BackupReader, FileTree};
 use num_enum::TryFromPrimitive;
 use plist::Value;
+use rayon::prelude::*;
 use rusqlite::{params, Connection, OpenFlags, Result as SqliteResult};
 use serde::{Deserialize, Serialize};
 use serde_json::{from_slice, to_vec};
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
 use std::convert::TryFrom;
 use std::io::{Cursor, Error, ErrorKind, Write};
 use std::iter::IntoIterator;
 use std::str::{from_utf8, Utf8Error};
-use std::sync::Arc;
+use std::sync::{Arc, Mutex};
 use tempfile::NamedTempFile;
 
 const DOMAIN: &str = "AppDomain-com.tencent.xin";
+/// Record lines transformed in parallel at a time, bounds the attachments held in memory.
+const TRANSFORM_CHUNK: usize = 256;
 
 #[derive(BinRead)]
 #[br(little, magic = b"\n")]
@@ -274,21 +279,23 @@ impl AttachMetadata {
             .unwrap_or(true)
     }
 
-    fn hash_checker(
+    /// Values the merge overrides, thumbnails regenerated from a similar image are left out.
+    fn overrides(
         recorder: &SqliteChatRecorder,
         attaches: &Attachments,
         old_hash: &HashMap<String, MetadataType>,
         new_hash: &HashMap<String, MetadataType>,
-    ) {
-        const CHECK_DIFFERENCE: bool = true;
-        if CHECK_DIFFERENCE {
-            for (key, (old, new)) in old_hash.keys().filter_map(|key| {
+    ) -> Vec<String> {
+        old_hash
+            .keys()
+            .filter_map(|key| {
                 old_hash.get(key).and_then(|val| {
                     new_hash
                         .get(key)
                         .and_then(|new_val| (val != new_val).then_some((key, (val, new_val))))
                 })
-            }) {
+            })
+            .filter(|(key, (old, new))| {
                 if let ("thum", MetadataType::Int(thum)) = (key.as_str(), new) {
                     let mut target = match (new_hash.get("img"), new_hash.get("hd")) {
                         (Some(MetadataType::Int(img)), Some(MetadataType::Int(hd))) => {
@@ -303,16 +310,54 @@ impl AttachMetadata {
                         // 因此把旧缩略图也加入对比
                         target.push(old);
                     }
-                    if !Self::thum_checker(recorder, attaches, target, thum) {
-                        // 存在相似高清图时跳过waring
-                        continue;
-                    }
+                    // 存在相似高清图时跳过waring
+                    Self::thum_checker(recorder, attaches, target, thum)
+                } else {
+                    true
                 }
-                warn!(r#"metadata override "{}": "{:?}" -> "{:?}""#, key, old, new);
+            })
+            .map(|(key, (old, new))| {
+                format!(r#"metadata override "{}": "{:?}" -> "{:?}""#, key, old, new)
+            })
+            .collect()
+    }
+
+    fn hash_checker(
+        recorder: &SqliteChatRecorder,
+        attaches: &Attachments,
+        old_hash: &HashMap<String, MetadataType>,
+        new_hash: &HashMap<String, MetadataType>,
+    ) {
+        const CHECK_DIFFERENCE: bool = true;
+        if CHECK_DIFFERENCE {
+            for message in Self::overrides(recorder, attaches, old_hash, new_hash) {
+                warn!("{}", message);
             }
         }
     }
 
+    /// What `merge` would change, without merging.
+    pub fn diff(
+        self,
+        recorder: &SqliteChatRecorder,
+        attaches: &Attachments,
+        old: Self,
+    ) -> Vec<String> {
+        let old_hash = old.into_map();
+        let hash = old_hash
+            .clone()
+            .into_iter()
+            .chain(self.hash)
+            .collect::<HashMap<_, _>>();
+        let mut changes = Self::overrides(recorder, attaches, &old_hash, &hash);
+        changes.extend(
+            hash.keys()
+                .filter(|key| !old_hash.contains_key(*key))
+                .map(|key| format!(r#"metadata add "{}""#, key)),
+        );
+        changes
+    }
+
     pub fn merge(self, recorder: &SqliteChatRecorder, attaches: &Attachments, old: Self) -> Self {
         let old_hash = old.into_map();
         let hash = old_hash.clone().into_iter().chain(self.hash).collect();
@@ -362,7 +407,7 @@ struct RecordLine {
 impl RecordLine {
     pub fn get_attach_hashs(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         account: &str,
         hashed_user: &str,
     ) -> HashMap<i64, String> {
@@ -444,7 +489,7 @@ impl RecordLine {
 
     pub fn get_audio(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -486,7 +531,7 @@ impl RecordLine {
 
     pub fn get_custom_app(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         account: &str,
         hashed_user: &str,
     ) -> Option<(AttachMetadata, Attachments)> {
@@ -587,7 +632,7 @@ impl RecordLine {
 
     pub fn get_image(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -658,7 +703,7 @@ impl RecordLine {
 
     pub fn get_video(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -671,7 +716,7 @@ impl RecordLine {
 
     fn get_image_small(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -683,7 +728,7 @@ impl RecordLine {
 
     fn get_image_hd(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -695,7 +740,7 @@ impl RecordLine {
 
     fn get_image_thum(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -730,7 +775,7 @@ impl RecordLine {
 
     fn get_file(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         backups: &HashMap<String, BackupFile>,
         account: &str,
         hashed_user: &str,
@@ -796,11 +841,13 @@ struct UserDB {
     wxid: String,
     name: String,
     head: String,
+    /// found while transforming, possibly on worker threads
+    issues: Arc<Mutex<Vec<(String, Issue)>>>,
 }
 
 impl UserDB {
     pub fn new(
-        backup: &Backup,
+        backup: &dyn BackupReader,
         account: String,
         file: &BackupFile,
         account_files: Vec<BackupFile>,
@@ -817,11 +864,11 @@ impl UserDB {
         user_db.match_path(backup, file)
     }
 
-    pub fn with(self, backup: &Backup, file: &BackupFile) -> Self {
+    pub fn with(self, backup: &dyn BackupReader, file: &BackupFile) -> Self {
         self.match_path(backup, file)
     }
 
-    fn match_path(mut self, backup: &Backup, file: &BackupFile) -> Self {
+    fn match_path(mut self, backup: &dyn BackupReader, file: &BackupFile) -> Self {
         lazy_static! {
             static ref MESSAGES: Regex = Regex::new(r"^message_\d+.sqlite$").unwrap();
         }
@@ -858,35 +905,48 @@ impl UserDB {
         self
     }
 
+    /// Names of the databases needed for import which were not found.
+    pub fn get_missing(&self) -> Vec<&'static str> {
+        [
+            (self.contact.is_none(), "contact"),
+            (self.messages.is_empty(), "message"),
+            (
+                self.setting.is_none() && self.kv_setting.is_none(),
+                "setting",
+            ),
+            (self.session.is_none(), "session"),
+        ]
+        .iter()
+        .filter(|(missing, _)| *missing)
+        .map(|(_, name)| *name)
+        .collect()
+    }
+
     pub fn is_complete(&self) -> bool {
-        let ret = self.contact.is_some()
-            && !self.messages.is_empty()
-            && (self.setting.is_some() || self.kv_setting.is_some())
-            && self.session.is_some();
-        if !ret {
+        let missing = self.get_missing();
+        if !missing.is_empty() {
             warn!(
-                "user {} ({}, {}) db lost some metadata: {}, {}, {}, {}, {}",
+                "user {} ({}, {}) db lost some metadata: {}",
                 self.account,
                 self.wxid,
                 self.name,
-                self.contact.is_some(),
-                !self.messages.is_empty(),
-                self.setting.is_some(),
-                self.kv_setting.is_some(),
-                self.session.is_some()
+                missing.join(", ")
             );
         }
-        ret
+        missing.is_empty()
     }
 
-    pub fn build(&mut self, backup: &Backup) -> Result<(), Box<dyn std::error::Error>> {
+    pub fn build(&mut self, backup: &dyn BackupReader) -> Result<(), Box<dyn std::error::Error>> {
         self.load_settings(backup)?;
         self.load_contacts()?;
         self.load_chats()?;
         Ok(())
     }
 
-    fn load_settings(&mut self, backup: &Backup) -> Result<(), Box<dyn std::error::Error>> {
+    fn load_settings(
+        &mut self,
+        backup: &dyn BackupReader,
+    ) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(setting) = &self.setting {
             let data = backup.read_file(setting)?;
             if let Some(array) = Value::from_reader(Cursor::new(data))
@@ -1030,6 +1090,79 @@ impl UserDB {
         Ok(())
     }
 
+    /// Message count and time range of every chat table, merged across message dbs.
+    fn get_chat_stats(&self) -> HashMap<String, ChatStats> {
+        let mut stats: HashMap<String, ChatStats> = HashMap::new();
+        for message in self.messages.iter() {
+            let tables = Self::get_conn(Some(message.clone())).and_then(|conn| match conn {
+                Some(conn) => {
+                    let names = conn
+                        .prepare(r#"SELECT name FROM sqlite_master where type='table' and name like "Chat\_%" ESCAPE '\'"#)?
+                        .query_map(params![], |row| row.get::<_, String>(0))?
+                        .collect::<SqliteResult<Vec<_>>>()?;
+                    names
+                        .into_iter()
+                        .map(|name| {
+                            conn.query_row(
+                                &format!(
+                                    "SELECT count(*), min(CreateTime), max(CreateTime) FROM {}",
+                                    name
+                                ),
+                                params![],
+                                |row| {
+                                    Ok((
+                                        name[5..].to_string(),
+                                        ChatStats {
+                                            messages: row.get(0)?,
+                                            first: row.get::<_, Option<i64>>(1)?.unwrap_or_default(),
+                                            last: row.get::<_, Option<i64>>(2)?.unwrap_or_default(),
+                                        },
+                                    ))
+                                },
+                            )
+                        })
+                        .collect::<SqliteResult<Vec<_>>>()
+                }
+                None => Ok(vec![]),
+            });
+            match tables {
+                Ok(tables) => {
+                    for (hash, stat) in tables {
+                        stats
+                            .entry(hash)
+                            .and_modify(|s| *s = s.merge(&stat))
+                            .or_insert(stat);
+                    }
+                }
+                Err(e) => warn!("failed to count chat messages: {}", e),
+            }
+        }
+        stats
+    }
+
+    fn get_chat_infos(&self) -> Vec<ChatInfo> {
+        let stats = self.get_chat_stats();
+        let mut infos = self
+            .chats
+            .keys()
+            .map(|hash| {
+                let contact = self.contacts.get(hash);
+                let wxid = contact.map(|c| c.name.clone()).unwrap_or_default();
+                ChatInfo {
+                    hash: hash.clone(),
+                    is_group: wxid.ends_with("@chatroom"),
+                    wxid,
+                    remark: contact
+                        .and_then(|c| c.get_remark().ok())
+                        .unwrap_or_default(),
+                    stats: stats.get(hash).copied().unwrap_or_default(),
+                }
+            })
+            .collect::<Vec<_>>();
+        infos.sort_by_key(|info| std::cmp::Reverse(info.stats.last));
+        infos
+    }
+
     fn get_chat_ids(&self) -> Vec<String> {
         self.chats.keys().cloned().collect::<Vec<_>>()
     }
@@ -1096,11 +1229,28 @@ impl UserDB {
             .collect()
     }
 
+    fn issue(&self, group_id: &str, issue: Issue) {
+        if let Ok(mut issues) = self.issues.lock() {
+            issues.push((group_id.into(), issue));
+        }
+    }
+
+    fn take_issues(&self) -> Vec<(String, Issue)> {
+        self.issues
+            .lock()
+            .map(|mut issues| std::mem::take(&mut *issues))
+            .unwrap_or_default()
+    }
+
     fn load_record_lines<S: ToString>(
         &self,
         user_name: S,
+        range: DateRange,
         skip_resource: bool,
     ) -> SqliteResult<Vec<RecordLine>> {
+        // CreateTime is in seconds
+        let (since, until) = range.bounds();
+        let (since, until) = (since.div_euclid(1000), until.div_euclid(1000));
         let mut lines = vec![];
         let user_name = user_name.to_string();
         let hash = self
@@ -1108,7 +1258,12 @@ impl UserDB {
             .keys()
             .find(|h| h.as_str() == user_name)
             .map(|s| s.into())
-            .unwrap_or_else(|| gen_md5(user_name));
+            .unwrap_or_else(|| gen_md5(&user_name));
+        let group_id = self
+            .contacts
+            .get(&hash)
+            .map(|contact| contact.name.clone())
+            .unwrap_or(user_name);
         for message in self.find_chat_table(&hash) {
             if let Some(conn) = Self::get_conn(Some(message.clone()))? {
                 lines.append(
@@ -1124,10 +1279,12 @@ impl UserDB {
                             Type,
                             Des
                         FROM
-                            Chat_{}",
+                            Chat_{}
+                        WHERE
+                            CreateTime >= ?1 AND CreateTime <= ?2",
                             hash
                         ))?
-                        .query_map(params![], |row| {
+                        .query_map(params![since, until], |row| {
                             Ok(RecordLine {
                                 local_id: row.get(0)?,
                                 server_id: row.get(1)?,
@@ -1138,6 +1295,10 @@ impl UserDB {
                                 msg_type: MsgType::try_from(row.get::<_, u32>(6)?).unwrap_or_else(
                                     |t| {
                                         warn!("unknown type: {}", t);
+                                        self.issue(
+                                            &group_id,
+                                            Issue::UnknownType(t.number.to_string()),
+                                        );
                                         MsgType::Unknown
                                     },
                                 ),
@@ -1153,6 +1314,8 @@ impl UserDB {
                 );
             }
         }
+        // a chat may span several message dbs, resuming needs them in time order
+        lines.sort_by_key(|line| (line.created_time, line.local_id));
         Ok(lines)
     }
 
@@ -1213,6 +1376,21 @@ impl UserDB {
             })
     }
 
+    fn get_account(&self) -> Account {
+        Account {
+            owner_id: self.wxid.clone(),
+            account: self.account.clone(),
+            nickname: self.name.clone(),
+        }
+    }
+
+    /// Match the account by its folder hash, wxid or nickname.
+    fn is_account(&self, name: &str) -> bool {
+        [&self.account, &self.wxid, &self.name]
+            .iter()
+            .any(|s| s.as_str() == name)
+    }
+
     fn get_microsecond(server_id: i64) -> i64 {
         use mur3::Hasher128;
         use std::hash::Hasher;
@@ -1223,7 +1401,7 @@ impl UserDB {
 
     fn transform_record_line(
         &self,
-        backup: &Backup,
+        backup: &dyn BackupReader,
         line: &RecordLine,
         contact: &Contact,
     ) -> Result<RecordType, String> {
@@ -1419,6 +1597,16 @@ impl UserDB {
             }
         }
 
+        if attach.is_empty()
+            && !line.skip_resource
+            && matches!(
+                line.msg_type,
+                MsgType::Image | MsgType::Video | MsgType::ShortVideo | MsgType::Voice
+            )
+        {
+            self.issue(&contact.name, Issue::MissingAttachment);
+        }
+
         let record = Record {
             chat_type: "WeChat".into(),
             owner_id: self.wxid.clone(),
@@ -1442,42 +1630,58 @@ impl UserDB {
         })
     }
 
-    fn transform_record_lines(
-        &self,
-        backup: &Backup,
-        contact: &Contact,
+    fn transform_record_lines<'a>(
+        &'a self,
+        backup: &'a dyn BackupReader,
+        contact: &'a Contact,
         lines: Vec<RecordLine>,
-    ) -> Vec<RecordType> {
-        lines
-            .iter()
-            .fold(Vec::<RecordType>::new(), |mut ret, curr| {
-                match self.transform_record_line(backup, curr, contact) {
-                    Ok(record_type) => ret.push(record_type),
-                    Err(e) => error!("failed to transform record line: {}", e),
-                };
-                ret
+    ) -> Records<'a> {
+        // attachments of a chunk are read, decrypted and hashed in parallel, order is kept
+        let mut lines = lines.into_iter();
+        Box::new(
+            std::iter::from_fn(move || {
+                let chunk = lines.by_ref().take(TRANSFORM_CHUNK).collect::<Vec<_>>();
+                (!chunk.is_empty()).then(|| {
+                    chunk
+                        .par_iter()
+                        .map(|line| {
+                            self.transform_record_line(backup, line, contact)
+                                .map_err(|e| {
+                                    anyhow::anyhow!(
+                                        "failed to transform record line {}: {}",
+                                        line.local_id,
+                                        e
+                                    )
+                                    .context(InChat(contact.name.clone()))
+                                })
+                        })
+                        .collect::<Vec<_>>()
+                })
             })
+            .flatten(),
+        )
     }
 
-    fn load_records<S: ToString>(
-        &self,
-        backup: &Backup,
-        chat_id: S,
+    fn load_records<'a>(
+        &'a self,
+        backup: &'a dyn BackupReader,
+        chat_id: &str,
+        range: DateRange,
         skip_resource: bool,
-    ) -> Option<Vec<RecordType>> {
-        let chat_id = chat_id.to_string();
-        self.contacts
-            .get(&chat_id)
-            .or_else(|| {
-                warn!("failed to get chat contact: {}", chat_id);
-                None
-            })
-            .and_then(|contact| {
-                self.load_record_lines(&chat_id, skip_resource)
-                    .map(|lines| self.transform_record_lines(backup, contact, lines))
-                    .map_err(|e| warn!("failed to get chat line: {}", e))
-                    .ok()
-            })
+    ) -> Records<'a> {
+        match self.contacts.get(chat_id) {
+            Some(contact) => {
+                match self.load_record_lines(chat_id, range, skip_resource) {
+                    Ok(lines) => self.transform_record_lines(backup, contact, lines),
+                    Err(e) => records_of::<RecordType, _>(Err(anyhow::Error::from(e)
+                        .context(format!("failed to get chat line: {}", chat_id)))),
+                }
+            }
+            None => records_of::<RecordType, _>(Err(anyhow::anyhow!(
+                "failed to get chat contact: {}",
+                chat_id
+            ))),
+        }
     }
 
     pub fn get_record_names(&self, names: Option<Vec<String>>) -> Vec<String> {
@@ -1488,50 +1692,124 @@ impl UserDB {
         }
     }
 
-    pub fn get_records(
+    fn select_chats(&self, name: &str, selected: Option<&HashSet<String>>) -> Vec<String> {
+        self.find_contacts(name)
+            .into_iter()
+            .filter(|chat_id| selected.is_none_or(|selected| selected.contains(chat_id)))
+            .collect()
+    }
+
+    /// Lines `load_record_lines` would return for the chat, without loading them.
+    fn count_record_lines(&self, hash: &str, range: DateRange) -> SqliteResult<u64> {
+        let (since, until) = range.bounds();
+        let (since, until) = (since.div_euclid(1000), until.div_euclid(1000));
+        let mut count = 0;
+        for message in self.find_chat_table(hash) {
+            if let Some(conn) = Self::get_conn(Some(message))? {
+                count += conn.query_row(
+                    &format!(
+                        "SELECT count(*) FROM Chat_{} WHERE CreateTime >= ?1 AND CreateTime <= ?2",
+                        hash
+                    ),
+                    params![since, until],
+                    |row| row.get::<_, i64>(0),
+                )? as u64;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Expected records of the selected chats by group_id.
+    pub fn count_records(
         &self,
-        backup: &Backup,
-        name: String,
-        skip_resource: bool,
-    ) -> Vec<RecordType> {
-        self.find_contacts(&name)
-            .iter()
+        name: &str,
+        selected: Option<&HashSet<String>>,
+        range: DateRange,
+        progress: &Progress,
+    ) -> HashMap<String, u64> {
+        self.select_chats(name, selected)
+            .into_iter()
             .filter_map(|chat_id| {
-                info!("Extracting: {} => {}", name, chat_id);
-                self.load_records(backup, chat_id, skip_resource)
+                let contact = self.contacts.get(&chat_id)?;
+                let range =
+                    range.resume_from(progress.get(&(self.wxid.clone(), contact.name.clone())));
+                self.count_record_lines(&chat_id, range)
+                    .map_err(|e| warn!("failed to count chat lines: {}, {}", chat_id, e))
+                    .ok()
+                    .map(|count| (contact.name.clone(), count))
             })
-            .flatten()
-            .collect::<Vec<_>>()
+            .collect()
+    }
+
+    pub fn get_records<'a>(
+        &'a self,
+        backup: &'a dyn BackupReader,
+        name: String,
+        selected: Option<&HashSet<String>>,
+        range: DateRange,
+        progress: &'a Progress,
+        skip_resource: bool,
+    ) -> Records<'a> {
+        let chat_ids = self.select_chats(&name, selected);
+        // chats are loaded one after another when the records are consumed
+        Box::new(chat_ids.into_iter().flat_map(move |chat_id| {
+            info!("Extracting: {} => {}", name, chat_id);
+            // the journal knows chats by group_id, the contact name of the hashed chat id
+            let range = range.resume_from(
+                self.contacts
+                    .get(&chat_id)
+                    .and_then(|contact| progress.get(&(self.wxid.clone(), contact.name.clone()))),
+            );
+            self.load_records(backup, &chat_id, range, skip_resource)
+        }))
     }
 }
 
 #[allow(non_camel_case_types)]
 struct Extractor {
-    backup: Backup,
+    backup: Arc<dyn BackupReader>,
     user_info: HashMap<String, UserDB>,
 }
 
 impl Extractor {
-    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
-        let mut backup = Backup::new(path)?;
-        if backup.manifest.is_encrypted {
-            backup.parse_keybag()?;
-            debug!("trying decrypt of backup keybag");
-            if let Some(ref mut kb) = backup.manifest.keybag.as_mut() {
-                let pass = rpassword::prompt_password("Backup Password: ")?;
-                kb.unlock_with_passcode(&pass);
-            }
-            backup.manifest.unlock_manifest();
-            backup.parse_manifest()?;
-            backup.unwrap_file_keys()?;
+    pub fn new(source: &IosSource) -> Result<Self, Box<dyn std::error::Error>> {
+        let backup = if source.is_backup() {
+            source.backup()? as Arc<dyn BackupReader>
         } else {
-            backup.parse_manifest()?;
-        }
-        let user_info = Self::get_user_info(&backup);
+            Arc::new(Self::init_tree(source.path())?) as Arc<dyn BackupReader>
+        };
+        // databases and attachments are all read from the app container
+        backup.prefetch(&backup.find_wildcard_paths(DOMAIN, "*"))?;
+        let user_info = Self::get_user_info(backup.as_ref());
         Ok(Self { backup, user_info })
     }
 
-    fn get_user_info(backup: &Backup) -> HashMap<String, UserDB> {
+    /// extracted app container or full filesystem dump
+    fn init_tree<P: AsRef<Path>>(path: P) -> Result<FileTree, Box<dyn std::error::Error>> {
+        let tree = FileTree::new(&path)?;
+        if tree.find_wildcard_paths(DOMAIN, "Documents/*").is_empty() {
+            FileTree::with_domain(path, DOMAIN)
+        } else {
+            Ok(tree)
+        }
+    }
+
+    fn get_user_info(backup: &dyn BackupReader) -> HashMap<String, UserDB> {
+        Self::find_users(backup)
+            .iter()
+            .filter(|(_, user_db)| user_db.is_complete())
+            .filter_map(|(user_id, user_db)| {
+                let mut user = user_db.clone();
+                user.build(backup)
+                    .map(|_| (user_id.clone(), user))
+                    .map_err(|e| warn!("failed to init user: {}", e))
+                    .ok()
+            })
+            .collect()
+    }
+
+    /// every account folder with any wechat database, complete or not
+    fn find_users(backup: &dyn BackupReader) -> HashMap<String, UserDB> {
         const MATCHED_NAME: [&str; 5] = [
             "WCDB_Contact.sqlite",
             "MM.sqlite",
@@ -1572,12 +1850,12 @@ impl Extractor {
                     }
                     if let Some(user) = user_map.remove(&user_id) {
                         let user: UserDB = user;
-                        user_map.insert(user_id, user.with(&backup, file));
+                        user_map.insert(user_id, user.with(backup, file));
                     } else {
                         user_map.insert(
                             user_id.clone(),
                             UserDB::new(
-                                &backup,
+                                backup,
                                 user_id.clone(),
                                 file,
                                 backup.find_wildcard_paths(
@@ -1595,48 +1873,274 @@ impl Extractor {
             }
         }
         user_map
-            .iter()
-            .filter(|(_, user_db)| user_db.is_complete())
-            .filter_map(|(user_id, user_db)| {
-                let mut user = user_db.clone();
-                user.build(&backup)
-                    .map(|_| (user_id.clone(), user))
-                    .map_err(|e| warn!("failed to init user: {}", e))
-                    .ok()
-            })
-            .collect()
     }
 
     pub fn get_users(&self) -> Vec<String> {
         self.user_info.keys().cloned().collect()
     }
 
-    pub fn get_user_db(&self, user: &str) -> Option<(&UserDB, &Backup)> {
-        self.user_info.get(user).map(|db| (db, &self.backup))
+    pub fn get_user_db(&self, user: &str) -> Option<(&UserDB, &dyn BackupReader)> {
+        self.user_info
+            .get(user)
+            .map(|db| (db, self.backup.as_ref()))
+    }
+}
+
+/// Message count and `CreateTime` range (seconds) of a chat.
+#[derive(Clone, Copy, Default, Serialize)]
+pub struct ChatStats {
+    pub messages: i64,
+    pub first: i64,
+    pub last: i64,
+}
+
+impl ChatStats {
+    fn merge(&self, other: &Self) -> Self {
+        Self {
+            messages: self.messages + other.messages,
+            first: self.first.min(other.first),
+            last: self.last.max(other.last),
+        }
+    }
+}
+
+struct ChatInfo {
+    hash: String,
+    wxid: String,
+    remark: String,
+    is_group: bool,
+    stats: ChatStats,
+}
+
+/// Chat selection on top of `-c` names, an empty filter selects everything.
+#[derive(Default)]
+pub struct ChatFilter {
+    /// account hashes, wxids or nicknames, empty selects every account
+    pub accounts: Vec<String>,
+    /// matched against hash, wxid and remark
+    pub pattern: Option<Regex>,
+    /// only group chats with `Some(true)`, only private chats with `Some(false)`
+    pub groups: Option<bool>,
+    /// chat must have messages in this range, seconds
+    pub active_since: Option<i64>,
+    pub active_until: Option<i64>,
+    pub min_messages: Option<i64>,
+    /// hashes, wxids or remarks, one per line
+    pub include: Option<Vec<String>>,
+    pub exclude: Vec<String>,
+}
+
+impl ChatFilter {
+    /// Read a chat list file, blank lines and `#` comments are skipped.
+    pub fn load_list<P: AsRef<Path>>(path: P) -> Result<Vec<String>> {
+        Ok(std::fs::read_to_string(&path)
+            .context(format!(
+                "Cannot read chat list: {}",
+                path.as_ref().display()
+            ))?
+            .lines()
+            .map(str::trim)
+            .filter(|line| !line.is_empty() && !line.starts_with('#'))
+            .map(String::from)
+            .collect())
+    }
+
+    fn is_account(&self, user_db: &UserDB) -> bool {
+        self.accounts.is_empty() || self.accounts.iter().any(|a| user_db.is_account(a))
+    }
+
+    fn is_empty(&self) -> bool {
+        self.pattern.is_none()
+            && self.groups.is_none()
+            && self.active_since.is_none()
+            && self.active_until.is_none()
+            && self.min_messages.is_none()
+            && self.include.is_none()
+            && self.exclude.is_empty()
+    }
+
+    fn matches(&self, chat: &ChatInfo) -> bool {
+        let listed = |list: &[String]| {
+            list.iter()
+                .any(|item| [&chat.hash, &chat.wxid, &chat.remark].contains(&item))
+        };
+        self.pattern.as_ref().is_none_or(|pattern| {
+            [&chat.hash, &chat.wxid, &chat.remark]
+                .iter()
+                .any(|s| pattern.is_match(s))
+        }) && self.groups.is_none_or(|groups| groups == chat.is_group)
+            && self
+                .active_since
+                .is_none_or(|since| chat.stats.last >= since)
+            && self
+                .active_until
+                .is_none_or(|until| chat.stats.first <= until)
+            && self
+                .min_messages
+                .is_none_or(|min| chat.stats.messages >= min)
+            && self.include.as_ref().is_none_or(|include| listed(include))
+            && !listed(&self.exclude)
+    }
+
+    /// Hashes of the selected chats, `None` when nothing is filtered.
+    fn select(&self, user_db: &UserDB) -> Option<HashSet<String>> {
+        (!self.is_empty()).then(|| {
+            user_db
+                .get_chat_infos()
+                .into_iter()
+                .filter(|chat| self.matches(chat))
+                .map(|chat| chat.hash)
+                .collect()
+        })
     }
 }
 
+/// Print every chat of every account in the backup which passes the filter.
+pub fn list_chats<P: AsRef<Path>>(path: P, filter: &ChatFilter) -> Result<()> {
+    let time = |sec: i64| {
+        chrono::Local
+            .timestamp_opt(sec, 0)
+            .single()
+            .map(|t| t.format("%Y-%m-%d").to_string())
+            .unwrap_or_default()
+    };
+    let extractor = Extractor::new(&IosSource::new(path)).map_err(|e| anyhow::anyhow!("{}", e))?;
+    for (user_db, _) in extractor
+        .get_users()
+        .iter()
+        .filter_map(|u| extractor.get_user_db(u))
+        .filter(|(user_db, _)| filter.is_account(user_db))
+    {
+        println!("{} ({}, {})", user_db.account, user_db.wxid, user_db.name);
+        for chat in user_db
+            .get_chat_infos()
+            .iter()
+            .filter(|chat| filter.matches(chat))
+        {
+            println!(
+                "  {}  {:<24} {:<16} {:<7} {:>7}  {} - {}",
+                chat.hash,
+                chat.wxid,
+                chat.remark,
+                if chat.is_group { "group" } else { "private" },
+                chat.stats.messages,
+                time(chat.stats.first),
+                time(chat.stats.last)
+            );
+        }
+    }
+    Ok(())
+}
+
+#[derive(Serialize)]
+pub struct WeChatAccount {
+    /// md5 of the wxid, the account folder name in the backup
+    pub account: String,
+    pub wxid: String,
+    pub nickname: String,
+    pub chats: usize,
+    pub messages: i64,
+    /// databases not found, accounts missing any of them are not imported
+    pub missing: Vec<&'static str>,
+}
+
+/// Every wechat account found in the backup, including incomplete ones.
+pub fn inspect_accounts(backup: &dyn BackupReader) -> Vec<WeChatAccount> {
+    let mut accounts = Extractor::find_users(backup)
+        .into_iter()
+        .map(|(account, mut user_db)| {
+            let missing = user_db.get_missing();
+            if missing.is_empty() {
+                if let Err(e) = user_db.build(backup) {
+                    warn!("failed to init user: {}", e);
+                }
+            }
+            let stats = user_db.get_chat_stats();
+            WeChatAccount {
+                account,
+                wxid: user_db.wxid,
+                nickname: user_db.name,
+                chats: stats.len(),
+                messages: stats.values().map(|s| s.messages).sum(),
+                missing,
+            }
+        })
+        .collect::<Vec<_>>();
+    accounts.sort_by(|a, b| a.account.cmp(&b.account));
+    accounts
+}
+
 #[allow(non_camel_case_types)]
 pub struct Matcher {
     extractor: Extractor,
     extract_ids: Vec<String>,
     names: Option<Vec<String>>,
+    filter: ChatFilter,
+    range: DateRange,
+    progress: Progress,
     skip_resource: bool,
 }
 
 impl Matcher {
-    pub fn new<P: AsRef<Path>>(path: P, names: Option<Vec<String>>) -> Result<Box<dyn MsgMatcher>> {
-        let extractor = Extractor::new(path).map_err(|e| anyhow::anyhow!("{}", e))?;
-        let extract_ids = extractor.get_users();
+    pub fn new(
+        source: &IosSource,
+        names: Option<Vec<String>>,
+        filter: ChatFilter,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        let extractor = Extractor::new(source).map_err(|e| anyhow::anyhow!("{}", e))?;
+        let extract_ids = extractor
+            .get_users()
+            .into_iter()
+            .filter(|u| {
+                extractor
+                    .get_user_db(u)
+                    .is_some_and(|(user_db, _)| filter.is_account(user_db))
+            })
+            .collect::<Vec<_>>();
+        if extract_ids.is_empty() && !filter.accounts.is_empty() {
+            return Err(anyhow::anyhow!(
+                "No account matches {}, found: {}",
+                filter.accounts.join(","),
+                extractor
+                    .get_users()
+                    .iter()
+                    .filter_map(|u| extractor.get_user_db(u))
+                    .map(|(user_db, _)| format!(
+                        "{} ({}, {})",
+                        user_db.account, user_db.wxid, user_db.name
+                    ))
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ));
+        }
         Ok(Box::new(Self {
             extractor,
             extract_ids,
             names,
+            filter,
+            range,
+            progress: Progress::new(),
             skip_resource: false,
         }) as Box<dyn MsgMatcher>)
     }
 }
 
+fn diff_metadata(
+    recorder: &SqliteChatRecorder,
+    attaches: &Attachments,
+    old: &[u8],
+    new: &[u8],
+) -> Vec<String> {
+    match (
+        from_slice::<AttachMetadata>(old),
+        from_slice::<AttachMetadata>(new),
+    ) {
+        (Ok(old), Ok(new)) => new.diff(recorder, attaches, old),
+        _ => vec!["metadata replaced".into()],
+    }
+}
+
 fn merge_metadata(
     recorder: &SqliteChatRecorder,
     attaches: &Attachments,
@@ -1662,25 +2166,110 @@ fn merge_metadata(
 }
 
 impl MsgMatcher for Matcher {
-    fn get_records(&self) -> Option<Vec<RecordType>> {
-        Some(
+    fn get_records(&self) -> Option<Records<'_>> {
+        Some(Box::new(
             self.extract_ids
                 .iter()
-                .filter_map(|u| self.extractor.get_user_db(u))
-                .flat_map(|(user_db, backup)| {
+                .filter_map(move |u| self.extractor.get_user_db(u))
+                .flat_map(move |(user_db, backup)| {
+                    let selected = self.filter.select(user_db);
                     user_db
                         .get_record_names(self.names.clone())
-                        .iter()
-                        .flat_map(|name| {
-                            user_db.get_records(backup, name.clone(), self.skip_resource)
+                        .into_iter()
+                        .flat_map(move |name| {
+                            user_db.get_records(
+                                backup,
+                                name,
+                                selected.as_ref(),
+                                self.range,
+                                &self.progress,
+                                self.skip_resource,
+                            )
                         })
-                        .collect::<Vec<_>>()
-                })
-                .collect(),
-        )
+                }),
+        ))
     }
 
     fn get_metadata_merger(&self) -> Option<SqliteMetadataMerger> {
         Some(merge_metadata)
     }
+
+    fn get_metadata_differ(&self) -> Option<SqliteMetadataDiffer> {
+        Some(diff_metadata)
+    }
+
+    fn resume(&mut self, progress: Progress) -> bool {
+        self.progress = progress;
+        true
+    }
+
+    fn count_records(&self) -> Option<HashMap<String, u64>> {
+        let mut totals = HashMap::new();
+        for (user_db, _) in self
+            .extract_ids
+            .iter()
+            .filter_map(|u| self.extractor.get_user_db(u))
+        {
+            let selected = self.filter.select(user_db);
+            for name in user_db.get_record_names(self.names.clone()) {
+                for (group_id, count) in
+                    user_db.count_records(&name, selected.as_ref(), self.range, &self.progress)
+                {
+                    *totals.entry(group_id).or_default() += count;
+                }
+            }
+        }
+        Some(totals)
+    }
+
+    fn take_issues(&self) -> Vec<(String, Issue)> {
+        self.extract_ids
+            .iter()
+            .filter_map(|u| self.extractor.get_user_db(u))
+            .flat_map(|(user_db, _)| user_db.take_issues())
+            .collect()
+    }
+
+    fn accounts(&self) -> Vec<Account> {
+        self.extract_ids
+            .iter()
+            .filter_map(|u| self.extractor.get_user_db(u))
+            .map(|(user_db, _)| user_db.get_account())
+            .collect()
+    }
+}
+
+#[test]
+fn test_chat_filter() {
+    let chat = |wxid: &str, messages, last| ChatInfo {
+        hash: gen_md5(wxid),
+        wxid: wxid.into(),
+        remark: "".into(),
+        is_group: wxid.ends_with("@chatroom"),
+        stats: ChatStats {
+            messages,
+            first: 0,
+            last,
+        },
+    };
+    let group = chat("123@chatroom", 10, 100);
+    let private = chat("wxid_abc", 1, 200);
+    assert!(ChatFilter::default().matches(&group));
+    let filter = ChatFilter {
+        groups: Some(true),
+        ..Default::default()
+    };
+    assert!(filter.matches(&group) && !filter.matches(&private));
+    let filter = ChatFilter {
+        pattern: Some(Regex::new("^wxid_").unwrap()),
+        active_since: Some(150),
+        ..Default::default()
+    };
+    assert!(!filter.matches(&group) && filter.matches(&private));
+    let filter = ChatFilter {
+        min_messages: Some(5),
+        exclude: vec!["123@chatroom".into()],
+        ..Default::default()
+    };
+    assert!(!filter.matches(&group) && !filter.matches(&private));
 }
@@ -0,0 +1,472 @@
+use super::*;
+use mailparse::{
+    addrparse, dateparse, parse_headers, parse_mail, DispositionType, MailAddr, MailHeader,
+    MailHeaderMap, ParsedMail,
+};
+use scraper::{Html, Node};
+use serde::Serialize;
+use serde_json::to_vec;
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use walkdir::WalkDir;
+
+#[derive(Serialize)]
+struct MailAttach {
+    name: String,
+    mimetype: String,
+    hash: i64,
+}
+
+#[derive(Serialize)]
+struct MailMetadata {
+    subject: String,
+    message_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    in_reply_to: Option<String>,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    to: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    cc: String,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    attaches: Vec<MailAttach>,
+}
+
+struct Mail {
+    message_id: String,
+    in_reply_to: Option<String>,
+    references: Vec<String>,
+    sender_id: String,
+    sender_name: String,
+    subject: String,
+    to: String,
+    cc: String,
+    timestamp: i64,
+    content: String,
+    attaches: Vec<(String, String, Vec<u8>)>,
+}
+
+impl Mail {
+    fn parse(data: &[u8]) -> Result<Self> {
+        let mail = parse_mail(data).map_err(|e| anyhow::anyhow!("Failed to parse mail: {}", e))?;
+        let (mut plain, mut html, mut attaches) = (None, None, vec![]);
+        Self::walk_parts(&mail, &mut plain, &mut html, &mut attaches);
+        Ok(Self {
+            content: plain
+                .or_else(|| html.as_deref().map(Self::html_text))
+                .unwrap_or_default()
+                .trim()
+                .into(),
+            attaches,
+            ..Self::from_headers(&mail.headers)?
+        })
+    }
+
+    /// Everything but the body, enough to thread mails without decoding them.
+    fn from_headers(headers: &[MailHeader]) -> Result<Self> {
+        lazy_static! {
+            static ref MSG_ID_MATCHER: Regex = Regex::new(r"<[^<>\s]+>").unwrap();
+        }
+        let header = |key: &str| headers.get_first_value(key).unwrap_or_default();
+        let ids = |key: &str| {
+            MSG_ID_MATCHER
+                .find_iter(&header(key))
+                .map(|m| m.as_str().to_string())
+                .collect::<Vec<_>>()
+        };
+        let timestamp = dateparse(&header("Date"))
+            .map_err(|e| anyhow::anyhow!("Failed to parse mail date: {}, {}", header("Date"), e))?
+            * 1000;
+        let (sender_id, sender_name) = addrparse(&header("From"))
+            .ok()
+            .and_then(|list| match list.first() {
+                Some(MailAddr::Single(info)) => Some((
+                    info.addr.clone(),
+                    info.display_name
+                        .clone()
+                        .unwrap_or_else(|| info.addr.clone()),
+                )),
+                Some(MailAddr::Group(group)) => {
+                    Some((group.group_name.clone(), group.group_name.clone()))
+                }
+                None => None,
+            })
+            .unwrap_or_else(|| (header("From"), header("From")));
+        Ok(Self {
+            // messages without an id can't be threaded, so use a stable replacement
+            message_id: ids("Message-ID")
+                .pop()
+                .unwrap_or_else(|| format!("<{}@{}>", timestamp, sender_id)),
+            in_reply_to: ids("In-Reply-To").pop(),
+            references: ids("References"),
+            sender_id,
+            sender_name,
+            subject: header("Subject"),
+            to: header("To"),
+            cc: header("Cc"),
+            timestamp,
+            content: String::new(),
+            attaches: vec![],
+        })
+    }
+
+    /// Visible text of a html body, scripts and styles left out.
+    fn html_text(html: &str) -> String {
+        let document = Html::parse_document(html);
+        document
+            .root_element()
+            .descendants()
+            .filter_map(|node| match node.value() {
+                Node::Text(text)
+                    if !node.ancestors().any(|parent| {
+                        matches!(parent.value(), Node::Element(e) if e.name() == "script" || e.name() == "style")
+                    }) =>
+                {
+                    Some(&**text)
+                }
+                _ => None,
+            })
+            .collect()
+    }
+
+    /// Attachments keyed by part index, names like image001.png repeat within a mail.
+    fn attachments(&self) -> Attachments {
+        self.attaches
+            .iter()
+            .enumerate()
+            .map(|(index, (name, _, data))| (format!("{}_{}", index, name), data.clone()))
+            .collect()
+    }
+
+    /// The first reference, or the mail replied to.
+    fn parent(&self) -> Option<String> {
+        self.references
+            .first()
+            .or(self.in_reply_to.as_ref())
+            .cloned()
+    }
+
+    fn walk_parts(
+        part: &ParsedMail,
+        plain: &mut Option<String>,
+        html: &mut Option<String>,
+        attaches: &mut Vec<(String, String, Vec<u8>)>,
+    ) {
+        if !part.subparts.is_empty() {
+            for sub in part.subparts.iter() {
+                Self::walk_parts(sub, plain, html, attaches);
+            }
+            return;
+        }
+        let disposition = part.get_content_disposition();
+        let name = disposition
+            .params
+            .get("filename")
+            .or_else(|| part.ctype.params.get("name"))
+            .cloned();
+        match (&disposition.disposition, name, part.ctype.mimetype.as_str()) {
+            (DispositionType::Attachment, name, _) | (_, name @ Some(_), _) => {
+                match part.get_body_raw() {
+                    Ok(data) => attaches.push((
+                        name.unwrap_or_else(|| format!("attach_{}", attaches.len())),
+                        part.ctype.mimetype.clone(),
+                        data,
+                    )),
+                    Err(e) => warn!("Failed to decode mail attach: {}", e),
+                }
+            }
+            (_, None, "text/plain") if plain.is_none() => *plain = part.get_body().ok(),
+            (_, None, "text/html") if html.is_none() => *html = part.get_body().ok(),
+            _ => {}
+        }
+    }
+}
+
+/// mboxrd: every message starts with a "From " line, body lines are escaped with ">"
+struct MboxMessages<R> {
+    reader: R,
+    current: Option<Vec<u8>>,
+}
+
+impl<R: BufRead> MboxMessages<R> {
+    fn new(reader: R) -> Self {
+        Self {
+            reader,
+            current: None,
+        }
+    }
+}
+
+impl<R: BufRead> Iterator for MboxMessages<R> {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        lazy_static! {
+            static ref ESCAPED_FROM: Regex = Regex::new(r"^>(>*From )").unwrap();
+        }
+        let mut line = vec![];
+        loop {
+            line.clear();
+            match self.reader.read_until(b'\n', &mut line) {
+                Ok(0) => return self.current.take(),
+                Ok(_) if line.starts_with(b"From ") => {
+                    if let Some(mail) = self.current.replace(vec![]) {
+                        return Some(mail);
+                    }
+                }
+                Ok(_) => {
+                    if let Some(mail) = self.current.as_mut() {
+                        match std::str::from_utf8(&line) {
+                            Ok(text) if ESCAPED_FROM.is_match(text) => {
+                                mail.extend_from_slice(&line[1..])
+                            }
+                            _ => mail.extend_from_slice(&line),
+                        }
+                    }
+                }
+                Err(e) => {
+                    warn!("Failed to read mbox: {}", e);
+                    return self.current.take();
+                }
+            }
+        }
+    }
+}
+
+enum MailSource {
+    Mbox(PathBuf),
+    Eml(PathBuf),
+}
+
+impl MailSource {
+    /// Raw messages, read one at a time.
+    fn messages(&self) -> Result<Box<dyn Iterator<Item = Result<Vec<u8>>> + '_>> {
+        Ok(match self {
+            Self::Mbox(path) => {
+                Box::new(MboxMessages::new(BufReader::new(File::open(path)?)).map(Ok))
+            }
+            Self::Eml(path) => Box::new(
+                WalkDir::new(path)
+                    .into_iter()
+                    .filter_map(|e| e.map(|item| item.into_path()).ok())
+                    .filter(|p| p.is_file() && p.ext_str().eq_ignore_ascii_case("eml"))
+                    .map(|p| {
+                        read(&p).map_err(|e| {
+                            anyhow::anyhow!("Failed to read eml: {}, {}", p.display(), e)
+                        })
+                    }),
+            ),
+        })
+    }
+}
+
+pub struct Matcher {
+    owner: String,
+    source: MailSource,
+    /// thread root of every message id
+    roots: HashMap<String, String>,
+    range: DateRange,
+}
+
+impl Matcher {
+    pub fn new_mbox<P: AsRef<Path>>(
+        path: P,
+        owner: String,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        info!("Parsing mbox...");
+        Self::from_source(MailSource::Mbox(path.as_ref().into()), owner, range)
+    }
+
+    pub fn new_eml<P: AsRef<Path>>(
+        path: P,
+        owner: String,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        info!("Parsing eml...");
+        Self::from_source(MailSource::Eml(path.as_ref().into()), owner, range)
+    }
+
+    fn from_source(
+        source: MailSource,
+        owner: String,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        // only headers are read here, bodies are decoded while importing
+        let parents = source
+            .messages()?
+            .filter_map(|data| {
+                let data = data.ok()?;
+                let (headers, _) = parse_headers(&data).ok()?;
+                Mail::from_headers(&headers).ok()
+            })
+            .map(|mail| {
+                let parent = mail.parent();
+                (mail.message_id, parent)
+            })
+            .collect();
+        Ok(Box::new(Self {
+            owner,
+            source,
+            roots: Self::get_thread_roots(&parents),
+            range,
+        }) as Box<dyn MsgMatcher>)
+    }
+
+    /// the thread root is the first reference, or the top of the known reply chain
+    fn get_thread_roots(parents: &HashMap<String, Option<String>>) -> HashMap<String, String> {
+        parents
+            .keys()
+            .map(|id| {
+                let mut root = id;
+                let mut depth = 0;
+                while let Some(Some(parent)) = parents.get(root) {
+                    root = parent;
+                    depth += 1;
+                    if depth > parents.len() {
+                        warn!("Reply loop found in thread: {}", id);
+                        break;
+                    }
+                }
+                (id.clone(), root.clone())
+            })
+            .collect()
+    }
+}
+
+impl MsgMatcher for Matcher {
+    fn get_records(&self) -> Option<Records<'_>> {
+        // out of range mails still take part in threading
+        let messages = match self.source.messages() {
+            Ok(messages) => messages,
+            Err(e) => return Some(Box::new(std::iter::once(Err(e)))),
+        };
+        Some(Box::new(
+            messages
+                .map(|data| data.and_then(|data| Mail::parse(&data)))
+                .filter(
+                    move |mail| !matches!(mail, Ok(mail) if !self.range.contains(mail.timestamp)),
+                )
+                .map(move |mail| {
+                    let mail = mail?;
+                    let metadata = MailMetadata {
+                        subject: mail.subject.clone(),
+                        message_id: mail.message_id.clone(),
+                        in_reply_to: mail.in_reply_to.clone(),
+                        to: mail.to.clone(),
+                        cc: mail.cc.clone(),
+                        attaches: mail
+                            .attaches
+                            .iter()
+                            .map(|(name, mimetype, data)| MailAttach {
+                                name: name.clone(),
+                                mimetype: mimetype.clone(),
+                                hash: Blob::new(data.clone()).hash,
+                            })
+                            .collect(),
+                    };
+                    let record = Record {
+                        chat_type: "Email".into(),
+                        owner_id: self.owner.clone(),
+                        group_id: self
+                            .roots
+                            .get(&mail.message_id)
+                            .unwrap_or(&mail.message_id)
+                            .clone(),
+                        sender_id: mail.sender_id.clone(),
+                        sender_name: mail.sender_name.clone(),
+                        content: mail.content.clone(),
+                        timestamp: mail.timestamp,
+                        metadata: to_vec(&metadata)
+                            .map_err(|e| warn!("failed to serialization metadata: {}", e))
+                            .ok(),
+                        ..Default::default()
+                    };
+                    Ok(if mail.attaches.is_empty() {
+                        RecordType::from(record)
+                    } else {
+                        RecordType::from((record, mail.attachments()))
+                    })
+                }),
+        ))
+    }
+}
+
+#[test]
+fn test_mbox_messages() {
+    let mails = MboxMessages::new(
+        &b"From a@b Sat Jan  3 01:05:34 1996\nSubject: 1\n\n>From here\nbody\n\nFrom c@d Sat Jan  3 01:05:34 1996\nSubject: 2\n\nbody\n"[..],
+    )
+    .collect::<Vec<_>>();
+    assert_eq!(mails.len(), 2);
+    assert_eq!(mails[0], b"Subject: 1\n\nFrom here\nbody\n\n".to_vec());
+    assert_eq!(mails[1], b"Subject: 2\n\nbody\n".to_vec());
+}
+
+#[test]
+fn test_mail_threading() {
+    let mail = |headers: &str| {
+        Mail::parse(
+            format!(
+                "{}\r\nFrom: a@b\r\nDate: Sat, 3 Jan 1996 01:05:34 +0000\r\n\r\nbody\r\n",
+                headers
+            )
+            .as_bytes(),
+        )
+        .unwrap()
+    };
+    let mails = [
+        mail("Message-ID: <root@b>"),
+        mail("Message-ID: <reply@b>\r\nIn-Reply-To: <root@b>"),
+        mail("Message-ID: <nested@b>\r\nIn-Reply-To: <reply@b>"),
+        mail("Message-ID: <late@b>\r\nReferences: <root@b> <nested@b>\r\nIn-Reply-To: <nested@b>"),
+        mail("Message-ID: <other@b>\r\nIn-Reply-To: <unknown@b>"),
+    ];
+    assert_eq!(mails[3].in_reply_to.as_deref(), Some("<nested@b>"));
+    let roots = Matcher::get_thread_roots(
+        &mails
+            .iter()
+            .map(|mail| (mail.message_id.clone(), mail.parent()))
+            .collect(),
+    );
+    for id in ["<root@b>", "<reply@b>", "<nested@b>", "<late@b>"] {
+        assert_eq!(roots[id], "<root@b>");
+    }
+    assert_eq!(roots["<other@b>"], "<unknown@b>");
+}
+
+#[test]
+fn test_mail_attachments() {
+    let mail = Mail::parse(
+        b"From: Someone <a@b>\r
+Date: Sat, 3 Jan 1996 01:05:34 +0000\r
+Message-ID: <mail@b>\r
+Content-Type: multipart/mixed; boundary=\"sep\"\r
+\r
+--sep\r
+Content-Type: text/html\r
+\r
+<html><head><style>p { color: red; }</style></head><body><p>Hello</p><script>alert(1)</script></body></html>\r
+--sep\r
+Content-Type: image/png\r
+Content-Disposition: attachment; filename=\"image001.png\"\r
+Content-Transfer-Encoding: base64\r
+\r
+Zmlyc3Q=\r
+--sep\r
+Content-Type: image/png\r
+Content-Disposition: attachment; filename=\"image001.png\"\r
+Content-Transfer-Encoding: base64\r
+\r
+c2Vjb25k\r
+--sep--\r
+",
+    )
+    .unwrap();
+    assert_eq!(mail.sender_name, "Someone");
+    assert_eq!(mail.content, "Hello");
+    let attachments = mail.attachments();
+    assert_eq!(attachments.len(), 2);
+    assert_eq!(attachments["0_image001.png"], b"first");
+    assert_eq!(attachments["1_image001.png"], b"second");
+}
@@ -1,10 +1,14 @@
 mod ios_sms;
 mod ios_wc;
+mod mail;
+mod slack;
+mod text_log;
 mod utils;
 mod win_qq_html;
 mod win_qq_mht;
 
 use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::{NaiveDate, NaiveTime};
 use gchdb::{Attachments, Blob, MetadataMerger, Record, RecordType};
 use htmlescape::decode_html;
 use lazy_static::lazy_static;
@@ -13,73 +17,407 @@ use path_ext::PathExt;
 use regex::{Captures, Regex};
 use utils::{blob_dhash, hamming_distance};
 
+pub use ios_sms::{inspect_chats, SmsChat};
+pub use ios_wc::{inspect_accounts, list_chats, ChatFilter, WeChatAccount};
+pub use text_log::TextLogProfile;
+
+use crate::summary::Issue;
+
 type SqliteMetadataMerger = MetadataMerger<SqliteChatRecorder>;
+/// Describes what merging the new metadata into the old one would change.
+pub type SqliteMetadataDiffer = fn(&SqliteChatRecorder, &Attachments, &[u8], &[u8]) -> Vec<String>;
+
+/// Batches written between commits of the search index, committing it is slow.
+const INDEX_BATCHES: usize = 10;
+
+/// Records in import order, produced lazily so memory stays bounded.
+/// A record that fails to transform doesn't stop the others.
+pub type Records<'a> = Box<dyn Iterator<Item = Result<RecordType<'a>>> + 'a>;
+
+/// Stream records of a chunk loaded at once, or the error that prevented loading it.
+fn records_of<'a, T, E>(chunk: std::result::Result<Vec<T>, E>) -> Records<'a>
+where
+    T: Into<RecordType<'a>> + 'a,
+    E: Into<anyhow::Error>,
+{
+    match chunk {
+        Ok(records) => Box::new(records.into_iter().map(|r| Ok(r.into()))),
+        Err(e) => Box::new(std::iter::once(Err(e.into()))),
+    }
+}
 
 pub trait MsgMatcher {
-    fn get_records(&self) -> Option<Vec<RecordType>>;
+    fn get_records(&self) -> Option<Records<'_>>;
     fn get_metadata_merger(&self) -> Option<SqliteMetadataMerger> {
         None
     }
+    fn get_metadata_differ(&self) -> Option<SqliteMetadataDiffer> {
+        None
+    }
+    /// Skip what an interrupted run already committed, false when the matcher can't.
+    fn resume(&mut self, _progress: Progress) -> bool {
+        false
+    }
+    /// Expected records per `group_id`, for progress and eta.
+    fn count_records(&self) -> Option<HashMap<String, u64>> {
+        None
+    }
+    /// Records skipped or degraded while transforming, by `group_id`.
+    fn take_issues(&self) -> Vec<(String, Issue)> {
+        vec![]
+    }
+    /// Accounts the records are imported from, kept by the journal rather than
+    /// in record metadata so imports of other backups don't change records.
+    fn accounts(&self) -> Vec<Account> {
+        vec![]
+    }
 }
 
+use crate::dry_run::DryRun;
+use crate::journal::Journal;
+use crate::progress_bar::ImportBars;
+use crate::snapshot::SnapshotLog;
+use crate::summary::Summary;
 use anyhow::{Context, Result};
 use gchdb::{ChatRecorder, SqliteChatRecorder};
+use ibackuptool2::Backup;
+use std::collections::HashMap;
 use std::fs::read;
-use std::path::Path;
-use std::time::Instant;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
 
 #[allow(non_camel_case_types)]
 pub enum ExportType<P: AsRef<Path>> {
     WindowsQQ(P, String),
-    iOSWeChat(P, Option<Vec<String>>),
-    iOSSMS(P, String),
+    iOSWeChat(IosSource, Option<Vec<String>>, ChatFilter),
+    iOSSMS(IosSource, String),
+    SlackExport(P, Option<String>),
+    Mbox(P, String),
+    Eml(P, String),
+    TextLog(P, TextLogProfile, String),
 }
 
-pub fn exporter<P>(recorder: &mut SqliteChatRecorder, export_type: ExportType<P>) -> Result<()>
+impl<P: AsRef<Path>> ExportType<P> {
+    /// Matcher name and source path, as recorded in the import journal.
+    pub fn source(&self) -> (&'static str, &Path) {
+        match self {
+            Self::WindowsQQ(path, _) => ("WindowsQQ", path.as_ref()),
+            Self::iOSWeChat(source, _, _) => ("iOSWeChat", source.path()),
+            Self::iOSSMS(source, _) => ("iOSSMS", source.path()),
+            Self::SlackExport(path, _) => ("Slack", path.as_ref()),
+            Self::Mbox(path, _) => ("Mbox", path.as_ref()),
+            Self::Eml(path, _) => ("Eml", path.as_ref()),
+            Self::TextLog(path, _, _) => ("TextLog", path.as_ref()),
+        }
+    }
+}
+
+/// Newest committed timestamp of each (owner_id, group_id) chat.
+pub type Progress = HashMap<(String, String), i64>;
+
+/// Account of the app that records of an `owner_id` were imported from.
+#[derive(Clone, Debug)]
+pub struct Account {
+    pub owner_id: String,
+    /// account folder in the backup
+    pub account: String,
+    pub nickname: String,
+}
+
+/// Inclusive range of message timestamps (ms) to import, open ends are unbounded.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DateRange {
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+}
+
+impl DateRange {
+    pub fn new((since, until): (Option<i64>, Option<i64>)) -> Self {
+        Self { since, until }
+    }
+
+    pub fn contains(&self, timestamp: i64) -> bool {
+        self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp <= until)
+    }
+
+    /// Whether any moment of the utc day is in range.
+    pub fn contains_day(&self, date: NaiveDate) -> bool {
+        let start = date.and_time(NaiveTime::MIN).and_utc().timestamp_millis();
+        let end = start + 24 * 60 * 60 * 1000 - 1;
+        self.since.is_none_or(|since| end >= since) && self.until.is_none_or(|until| start <= until)
+    }
+
+    /// Continue a chat from its last committed record, records of the same
+    /// timestamp are imported again, which merges them into themselves.
+    pub fn resume_from(self, last: Option<&i64>) -> Self {
+        Self {
+            since: match (self.since, last) {
+                (Some(since), Some(last)) => Some(since.max(*last)),
+                (since, last) => since.or(last.copied()),
+            },
+            ..self
+        }
+    }
+
+    /// Bounds for sql queries, open ends are replaced by the extremes.
+    pub fn bounds(&self) -> (i64, i64) {
+        (
+            self.since.unwrap_or(i64::MIN),
+            self.until.unwrap_or(i64::MAX),
+        )
+    }
+}
+
+#[derive(Default)]
+pub struct ExportOptions<'a> {
+    /// record in which backup snapshot each record was seen
+    pub snapshots: Option<&'a SnapshotLog>,
+    /// filtered by each matcher while loading
+    pub range: DateRange,
+    /// only compare records with the database
+    pub dry_run: Option<&'a DryRun>,
+    /// records written between checkpoints
+    pub batch_size: usize,
+    /// track progress, and skip what `--resume` finds committed
+    pub journal: Option<&'a Journal>,
+    /// count what happened to the records of each chat
+    pub summary: Option<&'a Summary>,
+}
+
+pub fn exporter<P>(
+    recorder: &mut SqliteChatRecorder,
+    export_type: ExportType<P>,
+    options: &ExportOptions,
+) -> Result<()>
 where
     P: AsRef<Path>,
 {
-    let matcher = match export_type {
-        ExportType::WindowsQQ(path, owner) => win_qq_mht::Matcher::new(
-            &read(&path)?,
-            owner,
-            path.as_ref()
+    let range = options.range;
+    let (name, source) = export_type.source();
+    let source = source.to_path_buf();
+    let progress = match options.journal {
+        Some(journal) => match journal.begin(name, &source)? {
+            Some(progress) => progress,
+            None => {
+                info!("Already imported, skipped: {}", source.display());
+                return Ok(());
+            }
+        },
+        None => Progress::new(),
+    };
+    let ios_source = match &export_type {
+        ExportType::iOSWeChat(source, _, _) | ExportType::iOSSMS(source, _) => Some(source.clone()),
+        _ => None,
+    };
+    let mut matcher = match export_type {
+        ExportType::WindowsQQ(path, owner) => {
+            let file_name = path
+                .as_ref()
                 .file_stem()
                 .and_then(|n| n.to_str())
                 .unwrap_or_default()
-                .into(),
-        )?,
-        ExportType::iOSWeChat(path, names) => ios_wc::Matcher::new(path, names)?,
-        ExportType::iOSSMS(path, owner) => ios_sms::Matcher::new(path, owner)?,
+                .into();
+            let data = read(&path)?;
+            match path.as_ref().ext_str().to_lowercase().as_str() {
+                "html" | "htm" => win_qq_mht::Matcher::from_html(
+                    String::from_utf8_lossy(&data).into_owned(),
+                    owner,
+                    file_name,
+                    range,
+                ),
+                _ => win_qq_mht::Matcher::new(&data, owner, file_name, range)?,
+            }
+        }
+        ExportType::iOSWeChat(source, names, filter) => {
+            ios_wc::Matcher::new(&source, names, filter, range)?
+        }
+        ExportType::iOSSMS(source, owner) => ios_sms::Matcher::new(&source, owner, range)?,
+        ExportType::SlackExport(path, workspace) => {
+            slack::Matcher::from_export(path, workspace, range)?
+        }
+        ExportType::Mbox(path, owner) => mail::Matcher::new_mbox(path, owner, range)?,
+        ExportType::Eml(path, owner) => mail::Matcher::new_eml(path, owner, range)?,
+        ExportType::TextLog(path, profile, owner) => {
+            text_log::Matcher::from_profile(path, profile, owner, range)?
+        }
     };
-    let records = matcher.get_records().context("Cannot transfrom records")?;
-    let mut progress = 0.0;
-    let mut sw = Instant::now();
-    for (i, record) in records.iter().enumerate() {
-        if (i + 1) as f64 / records.len() as f64 - progress > 0.01 {
-            progress = (i + 1) as f64 / records.len() as f64;
-            info!(
-                "current progress: {:.2}%, {}/{}, {}ms",
-                progress * 100.0,
-                i,
-                records.len(),
-                sw.elapsed().as_millis()
-            );
-            sw = Instant::now();
-        }
-        if !recorder
+    if let Some(journal) = options.journal {
+        // the backup the matcher opened, archives aren't indexed again
+        let backup = ios_source.and_then(|source| source.opened());
+        journal.provenance(backup.as_deref(), &matcher.accounts())?;
+    }
+    if !progress.is_empty() && !matcher.resume(progress) {
+        info!("Cannot resume {}, importing it again", name);
+    }
+    // matchers may transform records on the worker pool, writes only happen on this thread
+    let mut records = matcher.get_records().context("Cannot transfrom records")?;
+    let (mut count, mut failed) = (0, 0);
+    let mut bars = ImportBars::new(name, matcher.count_records());
+    for batches in 1.. {
+        let batch = records
+            .by_ref()
+            .take(options.batch_size.max(1))
+            .collect::<Vec<_>>();
+        if batch.is_empty() {
+            break;
+        }
+        let (written, errors) = write_batch(recorder, matcher.as_ref(), batch, options, &mut bars)?;
+        count += written;
+        failed += errors;
+        // checkpoint: everything written so far is indexed and resumable,
+        // an interrupted run imports the batches after it again
+        if options.dry_run.is_none() && batches % INDEX_BATCHES == 0 {
+            recorder.refresh_index()?;
+            if let Some(journal) = options.journal {
+                journal.checkpoint()?;
+            }
+        }
+    }
+    if options.dry_run.is_none() {
+        recorder.refresh_index()?;
+    }
+    bars.finish();
+    info!("{} records processed, {} failed", count, failed);
+    if let Some(summary) = options.summary {
+        summary.issues(matcher.take_issues());
+    }
+    if let Some(journal) = options.journal {
+        journal.finish()?;
+    }
+    Ok(())
+}
+
+/// Write a batch of records, returns how many were written and how many failed to transform.
+///
+/// gchdb commits every record on its own connection, what this importer tracks
+/// about them is committed once per batch.
+fn write_batch(
+    recorder: &mut SqliteChatRecorder,
+    matcher: &dyn MsgMatcher,
+    batch: Vec<Result<RecordType>>,
+    options: &ExportOptions,
+    bars: &mut ImportBars,
+) -> Result<(usize, usize)> {
+    let (mut count, mut failed) = (0, 0);
+    for record in batch {
+        let record = match record {
+            Ok(record) => record,
+            Err(e) => {
+                warn!("Failed to transform record: {:#}", e);
+                bars.failed();
+                if let Some(summary) = options.summary {
+                    summary.failed(&e);
+                }
+                failed += 1;
+                continue;
+            }
+        };
+        count += 1;
+        bars.record(&record);
+        if let Some(dry_run) = options.dry_run {
+            dry_run.check(recorder, &record, matcher.get_metadata_differ())?;
+            continue;
+        }
+        let change = options.summary.map(|s| s.classify(&record)).transpose()?;
+        let inserted = recorder
             .insert_or_update_record(record.clone(), matcher.get_metadata_merger())
-            .context(format!("Cannot insert records: {}", record.display()))?
-        {
+            .context(format!("Cannot insert records: {}", record.display()))?;
+        if let (Some(summary), Some(change)) = (options.summary, change) {
+            summary.written(&record, change, inserted);
+        }
+        if !inserted {
             let content = record
                 .get_record()
                 .map(|r| r.content.clone())
                 .unwrap_or_default();
             warn!("Failed to insert record: {}", content);
         }
+        if let (Some(snapshots), Some(record)) = (options.snapshots, record.get_record()) {
+            snapshots.seen(record);
+        }
+        if let (Some(journal), Some(record)) = (options.journal, record.get_record()) {
+            journal.committed(record);
+        }
+    }
+    if let Some(snapshots) = options.snapshots {
+        snapshots.commit()?;
+    }
+    Ok((count, failed))
+}
+
+static BACKUP_PASSWORD: std::sync::OnceLock<String> = std::sync::OnceLock::new();
+
+/// Password of encrypted backups, asked for on each backup when not set.
+pub fn set_backup_password(password: String) {
+    let _ = BACKUP_PASSWORD.set(password);
+}
+
+/// Open an itunes backup, asking for the password when it is encrypted.
+pub fn open_backup<P: AsRef<Path>>(path: P) -> Result<Backup> {
+    let open = || -> Result<Backup, Box<dyn std::error::Error>> {
+        let mut backup = Backup::new(path)?;
+        if backup.manifest.is_encrypted {
+            backup.parse_keybag()?;
+            debug!("trying decrypt of backup keybag");
+            if let Some(ref mut kb) = backup.manifest.keybag.as_mut() {
+                let pass = match BACKUP_PASSWORD.get() {
+                    Some(pass) => pass.clone(),
+                    None => rpassword::prompt_password("Backup Password: ")?,
+                };
+                kb.unlock_with_passcode(&pass);
+            }
+            backup.manifest.unlock_manifest();
+            backup.parse_manifest()?;
+            backup.unwrap_file_keys()?;
+        } else {
+            backup.parse_manifest()?;
+        }
+        Ok(backup)
+    };
+    open().map_err(|e| anyhow::anyhow!("{}", e))
+}
+
+/// An iOS backup, archive of one, or extracted folder given to a matcher.
+///
+/// Itunes backups are opened on first use, clones share the opened backup so
+/// the WeChat and SMS matchers of one backup list and decrypt it once.
+#[derive(Clone)]
+pub struct IosSource {
+    path: PathBuf,
+    backup: Arc<Mutex<Option<Arc<Backup>>>>,
+}
+
+impl IosSource {
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            backup: Arc::new(Mutex::new(None)),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Archives are listed for a manifest until the backup is opened.
+    pub fn is_backup(&self) -> bool {
+        self.opened().is_some() || Backup::is_backup(&self.path)
+    }
+
+    /// The itunes backup if a matcher opened it already.
+    pub fn opened(&self) -> Option<Arc<Backup>> {
+        self.backup.lock().ok().and_then(|backup| backup.clone())
+    }
+
+    /// The itunes backup, opened by the first caller.
+    pub fn backup(&self) -> Result<Arc<Backup>> {
+        let mut backup = self.backup.lock().map_err(|e| anyhow::anyhow!("{}", e))?;
+        match backup.as_ref() {
+            Some(backup) => Ok(backup.clone()),
+            None => Ok(backup.insert(Arc::new(open_backup(&self.path)?)).clone()),
+        }
     }
-    recorder.refresh_index()?;
-    Ok(())
 }
 
 fn gen_md5<S: ToString>(user_name: S) -> String {
@@ -125,3 +463,14 @@ fn modify_timestamp(record_type: RecordType, near_sec: Option<i64>) -> Option<Re
         Some(record_type)
     }
 }
+
+#[test]
+fn test_date_range() {
+    let range = DateRange::new((Some(1000), Some(2000)));
+    assert!(range.contains(1000) && range.contains(2000));
+    assert!(!range.contains(999) && !range.contains(2001));
+    assert!(DateRange::default().contains(i64::MIN));
+    let day = NaiveDate::from_ymd_opt(1970, 1, 1).unwrap();
+    assert!(range.contains_day(day));
+    assert!(!range.contains_day(day.succ_opt().unwrap()));
+}
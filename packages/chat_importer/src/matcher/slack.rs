@@ -0,0 +1,493 @@
+use super::*;
+use chrono::NaiveDate;
+use serde::{Deserialize, Serialize};
+use serde_json::{from_slice, to_vec};
+use std::collections::HashMap;
+use std::fs::File;
+use std::io::Read;
+use std::path::PathBuf;
+use std::sync::Mutex;
+use walkdir::WalkDir;
+use zip::ZipArchive;
+
+#[derive(Deserialize)]
+struct SlackProfile {
+    #[serde(default)]
+    display_name: String,
+    #[serde(default)]
+    real_name: String,
+}
+
+#[derive(Deserialize)]
+struct SlackUser {
+    id: String,
+    #[serde(default)]
+    name: String,
+    #[serde(default)]
+    real_name: String,
+    profile: Option<SlackProfile>,
+}
+
+impl SlackUser {
+    fn display_name(&self) -> String {
+        self.profile
+            .as_ref()
+            .and_then(|p| {
+                [&p.display_name, &p.real_name]
+                    .iter()
+                    .find(|s| !s.is_empty())
+                    .map(|s| s.to_string())
+            })
+            .or_else(|| (!self.real_name.is_empty()).then(|| self.real_name.clone()))
+            .unwrap_or_else(|| self.name.clone())
+    }
+}
+
+#[derive(Deserialize)]
+struct SlackChannel {
+    id: String,
+    name: Option<String>,
+    #[serde(default)]
+    members: Vec<String>,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct SlackReaction {
+    name: String,
+    #[serde(default)]
+    users: Vec<String>,
+    #[serde(default)]
+    count: u32,
+}
+
+#[derive(Clone, Deserialize, Serialize)]
+struct SlackFile {
+    #[serde(default)]
+    id: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    title: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    mimetype: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    size: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    url_private: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct SlackMessage {
+    #[serde(default)]
+    subtype: Option<String>,
+    #[serde(default)]
+    user: Option<String>,
+    #[serde(default)]
+    bot_id: Option<String>,
+    #[serde(default)]
+    username: Option<String>,
+    #[serde(default)]
+    text: String,
+    ts: String,
+    #[serde(default)]
+    thread_ts: Option<String>,
+    #[serde(default)]
+    reply_count: Option<u32>,
+    #[serde(default)]
+    reactions: Vec<SlackReaction>,
+    #[serde(default)]
+    files: Vec<SlackFile>,
+}
+
+#[derive(Default, Serialize)]
+struct SlackMetadata {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    subtype: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    thread_ts: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    reply_count: Option<u32>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    reactions: Vec<SlackReaction>,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    files: Vec<SlackFile>,
+}
+
+impl SlackMetadata {
+    fn is_empty(&self) -> bool {
+        self.subtype.is_none()
+            && self.thread_ts.is_none()
+            && self.reply_count.is_none()
+            && self.reactions.is_empty()
+            && self.files.is_empty()
+    }
+}
+
+/// An export folder, or its zip read a file at a time.
+enum SlackSource {
+    Dir(PathBuf),
+    Zip {
+        archive: Mutex<ZipArchive<File>>,
+        /// export archives are sometimes wrapped in a top level folder
+        prefix: String,
+        /// json files, relative to the prefix
+        names: Vec<String>,
+    },
+}
+
+impl SlackSource {
+    fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            Ok(Self::Dir(path.into()))
+        } else {
+            let archive = ZipArchive::new(File::open(path)?)?;
+            let prefix = archive
+                .file_names()
+                .filter(|name| *name == "users.json" || name.ends_with("/users.json"))
+                .min_by_key(|name| name.len())
+                .map(|name| name.trim_end_matches("users.json").to_string())
+                .unwrap_or_default();
+            let names = archive
+                .file_names()
+                .filter(|name| name.ends_with(".json"))
+                .filter_map(|name| name.strip_prefix(&prefix))
+                .map(String::from)
+                .collect();
+            Ok(Self::Zip {
+                archive: Mutex::new(archive),
+                prefix,
+                names,
+            })
+        }
+    }
+
+    fn read(&self, name: &str) -> Option<Vec<u8>> {
+        match self {
+            Self::Dir(root) => read(root.join(name)).ok(),
+            Self::Zip {
+                archive, prefix, ..
+            } => {
+                let mut archive = archive.lock().ok()?;
+                let mut file = archive.by_name(&format!("{}{}", prefix, name)).ok()?;
+                let mut data = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut data)
+                    .map_err(|e| warn!("Failed to read {}: {}", name, e))
+                    .ok()?;
+                Some(data)
+            }
+        }
+    }
+
+    fn list(&self, folder: &str) -> Vec<String> {
+        let mut days = match self {
+            Self::Dir(root) => WalkDir::new(root.join(folder))
+                .max_depth(1)
+                .into_iter()
+                .filter_map(|e| e.ok())
+                .filter(|e| e.path().is_file() && e.path().ext_str() == "json")
+                .map(|e| format!("{}/{}", folder, e.path().name_str()))
+                .collect::<Vec<_>>(),
+            Self::Zip { names, .. } => {
+                let prefix = format!("{}/", folder);
+                names
+                    .iter()
+                    .filter_map(|name| name.strip_prefix(&prefix))
+                    .filter(|name| !name.contains('/'))
+                    .map(|name| format!("{}/{}", folder, name))
+                    .collect()
+            }
+        };
+        days.sort();
+        days
+    }
+
+    fn load<T: for<'de> Deserialize<'de>>(&self, name: &str) -> Vec<T> {
+        self.read(name)
+            .map(|data| {
+                from_slice(&data)
+                    .map_err(|e| warn!("Failed to parse {}: {}", name, e))
+                    .unwrap_or_default()
+            })
+            .unwrap_or_default()
+    }
+}
+
+pub struct Matcher {
+    source: SlackSource,
+    workspace: String,
+    users: HashMap<String, String>,
+    channels: Vec<(String, String)>,
+    range: DateRange,
+    progress: Progress,
+}
+
+impl Matcher {
+    pub fn from_export<P: AsRef<Path>>(
+        path: P,
+        workspace: Option<String>,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        let source = SlackSource::open(&path)?;
+        let users = source
+            .load::<SlackUser>("users.json")
+            .into_iter()
+            .map(|user| (user.id.clone(), user.display_name()))
+            .collect::<HashMap<_, _>>();
+        if users.is_empty() {
+            return Err(anyhow::anyhow!("Failed to find slack users.json"));
+        }
+        let channels = ["channels.json", "groups.json", "mpims.json", "dms.json"]
+            .iter()
+            .flat_map(|name| source.load::<SlackChannel>(name))
+            .map(|SlackChannel { id, name, members }| {
+                let folder = name.clone().unwrap_or(id);
+                let group_id = name.unwrap_or_else(|| {
+                    members
+                        .iter()
+                        .map(|id| users.get(id).unwrap_or(id).as_str())
+                        .collect::<Vec<_>>()
+                        .join(",")
+                });
+                (folder, group_id)
+            })
+            .collect();
+        Ok(Box::new(Self {
+            source,
+            workspace: workspace.unwrap_or_else(|| {
+                path.as_ref()
+                    .file_stem()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default()
+                    .into()
+            }),
+            users,
+            channels,
+            range,
+            progress: Progress::new(),
+        }) as Box<dyn MsgMatcher>)
+    }
+
+    fn resolve_mentions(&self, text: &str) -> String {
+        lazy_static! {
+            static ref USER_MATCHER: Regex = Regex::new(r"<@(?P<id>[A-Z0-9]+)(\|[^>]*)?>").unwrap();
+            static ref CHANNEL_MATCHER: Regex =
+                Regex::new(r"<#[A-Z0-9]+\|(?P<name>[^>]*)>").unwrap();
+        }
+        let text = USER_MATCHER.replace_all(text, |c: &Captures| {
+            format!(
+                "@{}",
+                self.users
+                    .get(&c["id"])
+                    .map(String::as_str)
+                    .unwrap_or(&c["id"])
+            )
+        });
+        let text = CHANNEL_MATCHER.replace_all(&text, "#$name");
+        decode_html(&text).unwrap_or_else(|_| text.into())
+    }
+
+    fn parse_ts(ts: &str) -> Option<i64> {
+        let (sec, micro) = ts.split_once('.').unwrap_or((ts, "0"));
+        Some(sec.parse::<i64>().ok()? * 1000 + micro.parse::<i64>().unwrap_or(0) / 1000)
+    }
+
+    fn transform_message(&self, group_id: &str, msg: SlackMessage) -> Option<RecordType<'_>> {
+        let timestamp = Self::parse_ts(&msg.ts).or_else(|| {
+            warn!("Failed to parse slack ts: {}, {}", group_id, msg.ts);
+            None
+        })?;
+        let sender_id = msg
+            .user
+            .clone()
+            .or_else(|| msg.bot_id.clone())
+            .unwrap_or_default();
+        let sender_name = msg
+            .user
+            .as_ref()
+            .and_then(|id| self.users.get(id).cloned())
+            .or_else(|| msg.username.clone())
+            .unwrap_or_else(|| sender_id.clone());
+        let metadata = SlackMetadata {
+            subtype: msg.subtype,
+            // thread_ts equals ts on the parent message of a thread
+            thread_ts: msg.thread_ts,
+            reply_count: msg.reply_count,
+            reactions: msg
+                .reactions
+                .into_iter()
+                .map(|reaction| SlackReaction {
+                    users: reaction
+                        .users
+                        .iter()
+                        .map(|id| self.users.get(id).unwrap_or(id).clone())
+                        .collect(),
+                    ..reaction
+                })
+                .collect(),
+            files: msg.files,
+        };
+        Some(RecordType::from(Record {
+            chat_type: "Slack".into(),
+            owner_id: self.workspace.clone(),
+            group_id: group_id.into(),
+            sender_id,
+            sender_name,
+            content: self.resolve_mentions(&msg.text),
+            timestamp,
+            metadata: (!metadata.is_empty())
+                .then(|| {
+                    to_vec(&metadata)
+                        .map_err(|e| warn!("failed to serialization metadata: {}", e))
+                        .ok()
+                })
+                .flatten(),
+            ..Default::default()
+        }))
+    }
+
+    /// Day files are loaded one after another, from the last committed day
+    /// when resuming.
+    fn load_channel<'a>(
+        &'a self,
+        folder: &str,
+        group_id: &'a str,
+    ) -> impl Iterator<Item = RecordType<'a>> + 'a {
+        let range = self.range.resume_from(
+            self.progress
+                .get(&(self.workspace.clone(), group_id.to_string())),
+        );
+        self.source
+            .list(folder)
+            .into_iter()
+            .filter(move |day| {
+                Path::new(day)
+                    .file_stem()
+                    .and_then(|n| n.to_str())
+                    .and_then(|n| NaiveDate::parse_from_str(n, "%Y-%m-%d").ok())
+                    // day files are named by utc date
+                    .is_some_and(|day| range.contains_day(day))
+            })
+            .flat_map(move |day| self.source.load::<SlackMessage>(&day))
+            .filter(move |msg| Self::parse_ts(&msg.ts).is_none_or(|ts| range.contains(ts)))
+            .filter_map(move |msg| self.transform_message(group_id, msg))
+    }
+}
+
+impl MsgMatcher for Matcher {
+    fn get_records(&self) -> Option<Records<'_>> {
+        Some(Box::new(self.channels.iter().flat_map(
+            move |(folder, group_id)| {
+                info!("Extracting: {} => {}", folder, group_id);
+                self.load_channel(folder, group_id).map(Ok)
+            },
+        )))
+    }
+
+    fn resume(&mut self, progress: Progress) -> bool {
+        self.progress = progress;
+        true
+    }
+}
+
+#[test]
+fn test_slack_ts() {
+    assert_eq!(Matcher::parse_ts("1500000000.000100"), Some(1500000000000));
+    assert_eq!(Matcher::parse_ts("1500000000.123456"), Some(1500000000123));
+    assert_eq!(Matcher::parse_ts("1500000000"), Some(1500000000000));
+    assert_eq!(Matcher::parse_ts("abc"), None);
+}
+
+#[cfg(test)]
+const TEST_EXPORT: [(&str, &str); 5] = [
+    (
+        "users.json",
+        r#"[{"id": "U1", "name": "alice", "profile": {"display_name": "Alice"}},
+        {"id": "U2", "name": "bob", "real_name": "Bob B"}]"#,
+    ),
+    ("channels.json", r#"[{"id": "C1", "name": "general"}]"#),
+    ("dms.json", r#"[{"id": "D1", "members": ["U1", "U2"]}]"#),
+    (
+        "general/2017-07-14.json",
+        r#"[{"user": "U1", "text": "hi <@U2> see <#C1|general>", "ts": "1500000000.000100",
+        "thread_ts": "1500000000.000100", "reply_count": 1},
+        {"user": "U2", "text": "reply", "ts": "1500000060.000200", "thread_ts": "1500000000.000100"}]"#,
+    ),
+    (
+        "D1/2017-07-14.json",
+        r#"[{"user": "U2", "text": "&lt;dm&gt;", "ts": "1500000120.000000"}]"#,
+    ),
+];
+
+#[cfg(test)]
+fn check_test_export(path: &Path) {
+    let matcher = Matcher::from_export(path, Some("team".into()), DateRange::default()).unwrap();
+    let records = matcher
+        .get_records()
+        .unwrap()
+        .map(|record| record.unwrap().get_record().unwrap().clone())
+        .collect::<Vec<_>>();
+    let thread_ts = |record: &Record| {
+        serde_json::from_slice::<serde_json::Value>(record.metadata.as_ref()?)
+            .ok()?
+            .get("thread_ts")?
+            .as_str()
+            .map(String::from)
+    };
+    assert_eq!(records.len(), 3);
+    assert_eq!(records[0].group_id, "general");
+    assert_eq!(records[0].sender_name, "Alice");
+    assert_eq!(records[0].content, "hi @Bob B see #general");
+    assert_eq!(records[1].sender_name, "Bob B");
+    assert_eq!(records[1].timestamp, 1500000060000);
+    // replies point at the thread parent
+    assert_eq!(thread_ts(&records[1]).as_deref(), Some("1500000000.000100"));
+    assert_eq!(thread_ts(&records[0]), thread_ts(&records[1]));
+    assert_eq!(records[2].group_id, "Alice,Bob B");
+    assert_eq!(records[2].content, "<dm>");
+    assert!(records.iter().all(|record| record.owner_id == "team"));
+}
+
+#[test]
+fn test_slack_export_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    for (name, data) in TEST_EXPORT {
+        let path = dir.path().join(name);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(path, data).unwrap();
+    }
+    check_test_export(dir.path());
+
+    let mut matcher =
+        Matcher::from_export(dir.path(), Some("team".into()), DateRange::default()).unwrap();
+    let progress = Progress::from([(("team".into(), "general".into()), 1500000060000)]);
+    assert!(matcher.resume(progress));
+    let contents = matcher
+        .get_records()
+        .unwrap()
+        .map(|record| record.unwrap().get_record().unwrap().content.clone())
+        .collect::<Vec<_>>();
+    assert_eq!(contents, ["reply", "<dm>"]);
+}
+
+#[test]
+fn test_slack_export_zip() {
+    use std::io::Write;
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("export.zip");
+    let mut zip = zip::ZipWriter::new(File::create(&path).unwrap());
+    // wrapped in a top level folder, with a nested copy of users.json
+    for (name, data) in TEST_EXPORT
+        .iter()
+        .chain([("general/old/users.json", "[]")].iter())
+    {
+        zip.start_file(
+            format!("Team Slack export/{}", name),
+            zip::write::SimpleFileOptions::default(),
+        )
+        .unwrap();
+        zip.write_all(data.as_bytes()).unwrap();
+    }
+    zip.finish().unwrap();
+    check_test_export(&path);
+}
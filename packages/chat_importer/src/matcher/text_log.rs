@@ -0,0 +1,333 @@
+use super::*;
+use chrono::{FixedOffset, NaiveDateTime, TimeZone};
+use serde::Deserialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+
+const LINE_PROFILE: &str = r#"
+chat_type = "LINE"
+date_pattern = '^(?P<date>\d{4}/\d{2}/\d{2})\(\w+\)$'
+pattern = '^(?P<time>\d{1,2}:\d{2})\t(?P<sender>[^\t]+)\t(?P<text>.*)$'
+date_format = "%Y/%m/%d %H:%M"
+"#;
+
+const KAKAO_PROFILE: &str = r#"
+chat_type = "KakaoTalk"
+pattern = '^(?P<datetime>\w+ \d{1,2}, \d{4} at \d{1,2}:\d{2} [AP]M), (?P<sender>.+?) : (?P<text>.*)$'
+date_format = "%B %d, %Y at %I:%M %p"
+"#;
+
+/// Korean locale exports, the meridiem is translated before parsing.
+const KAKAO_KO_PROFILE: &str = r#"
+chat_type = "KakaoTalk"
+pattern = '^(?P<datetime>\d{4}년 \d{1,2}월 \d{1,2}일 (오전|오후) \d{1,2}:\d{2}), (?P<sender>.+?) : (?P<text>.*)$'
+date_format = "%Y년 %m월 %d일 %p %I:%M"
+"#;
+
+const SIGNAL_PROFILE: &str = r#"
+chat_type = "Signal"
+pattern = '^\[(?P<datetime>\d{4}-\d{2}-\d{2} \d{2}:\d{2})\] (?P<sender>[^:]+): (?P<text>.*)$'
+date_format = "%Y-%m-%d %H:%M"
+"#;
+
+/// Describes a plain text chat log where each message starts with a matchable line.
+///
+/// `pattern` must capture `sender` and `text`, and either `datetime` or `time`;
+/// with `time` the date comes from the last line matching `date_pattern`.
+#[derive(Clone, Deserialize)]
+pub struct TextLogProfile {
+    pub chat_type: String,
+    pub pattern: String,
+    pub date_pattern: Option<String>,
+    pub date_format: String,
+    /// fixed utc offset like "+09:00", defaults to utc
+    pub timezone: Option<String>,
+    /// defaults to the log file name
+    pub chat_id: Option<String>,
+}
+
+impl TextLogProfile {
+    /// Load a built-in profile by name, or a toml/json profile from path.
+    pub fn load(name: &str) -> Result<Self> {
+        match name.to_lowercase().as_str() {
+            "line" => Ok(toml::from_str(LINE_PROFILE)?),
+            "kakao" | "kakaotalk" => Ok(toml::from_str(KAKAO_PROFILE)?),
+            "kakao-ko" | "kakaotalk-ko" => Ok(toml::from_str(KAKAO_KO_PROFILE)?),
+            "signal" => Ok(toml::from_str(SIGNAL_PROFILE)?),
+            _ => {
+                let data = std::fs::read_to_string(name)
+                    .context(format!("Cannot read text log profile: {}", name))?;
+                if Path::new(name).ext_str() == "json" {
+                    Ok(serde_json::from_str(&data)?)
+                } else {
+                    Ok(toml::from_str(&data)?)
+                }
+            }
+        }
+    }
+
+    /// The built-in profile whose message lines appear in the first lines of a log.
+    pub fn detect<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let lines = BufReader::new(File::open(path).ok()?)
+            .lines()
+            .take(50)
+            .filter_map(|line| line.ok())
+            .collect::<Vec<_>>();
+        [
+            LINE_PROFILE,
+            KAKAO_PROFILE,
+            KAKAO_KO_PROFILE,
+            SIGNAL_PROFILE,
+        ]
+        .iter()
+        .filter_map(|profile| toml::from_str::<Self>(profile).ok())
+        .find(|profile| {
+            Regex::new(&profile.pattern).is_ok_and(|pattern| {
+                lines
+                    .iter()
+                    .any(|line| pattern.is_match(line.trim_end_matches('\r')))
+            })
+        })
+    }
+}
+
+struct TextLogLine {
+    sender: String,
+    text: String,
+    timestamp: i64,
+}
+
+pub struct Matcher {
+    owner: String,
+    chat_type: String,
+    chat_id: String,
+    path: PathBuf,
+    pattern: Regex,
+    date_pattern: Option<Regex>,
+    date_format: String,
+    offset: FixedOffset,
+    range: DateRange,
+}
+
+impl Matcher {
+    pub fn from_profile<P: AsRef<Path>>(
+        path: P,
+        profile: TextLogProfile,
+        owner: String,
+        range: DateRange,
+    ) -> Result<Box<dyn MsgMatcher>> {
+        let pattern = Regex::new(&profile.pattern)?;
+        let date_pattern = profile
+            .date_pattern
+            .as_ref()
+            .map(|p| Regex::new(p))
+            .transpose()?;
+        let offset = match profile.timezone.as_deref() {
+            None | Some("UTC") | Some("utc") => FixedOffset::east_opt(0).unwrap(),
+            Some(tz) => tz
+                .parse::<FixedOffset>()
+                .map_err(|e| anyhow::anyhow!("Invalid timezone {}: {}", tz, e))?,
+        };
+        // the log itself is read while importing
+        File::open(&path)?;
+        Ok(Box::new(Self {
+            owner,
+            chat_type: profile.chat_type,
+            chat_id: profile.chat_id.unwrap_or_else(|| {
+                path.as_ref()
+                    .file_stem()
+                    .and_then(|n| n.to_str())
+                    .unwrap_or_default()
+                    .into()
+            }),
+            path: path.as_ref().into(),
+            pattern,
+            date_pattern,
+            date_format: profile.date_format,
+            offset,
+            range,
+        }) as Box<dyn MsgMatcher>)
+    }
+
+    fn parse_line(&self, c: &Captures, date: Option<&String>, line: &str) -> Option<TextLogLine> {
+        let datetime = match (c.name("datetime"), c.name("time"), date) {
+            (Some(datetime), _, _) => datetime.as_str().to_string(),
+            (None, Some(time), Some(date)) => format!("{} {}", date, time.as_str()),
+            _ => {
+                warn!("No date found for line: {}", line);
+                return None;
+            }
+        };
+        // chrono only parses the english meridiem
+        let datetime = datetime.replace("오전", "AM").replace("오후", "PM");
+        match NaiveDateTime::parse_from_str(&datetime, &self.date_format) {
+            Ok(time) => Some(TextLogLine {
+                sender: c["sender"].trim().into(),
+                text: c["text"].into(),
+                timestamp: self
+                    .offset
+                    .from_local_datetime(&time)
+                    .single()
+                    .map(|t| t.timestamp_millis())
+                    .unwrap_or_else(|| time.and_utc().timestamp_millis()),
+            }),
+            Err(e) => {
+                warn!("Failed to parse time: {}, {}", datetime, e);
+                None
+            }
+        }
+    }
+}
+
+/// Messages of a log, parsed line by line while reading it.
+struct TextLogLines<'a, R> {
+    matcher: &'a Matcher,
+    reader: R,
+    date: Option<String>,
+    current: Option<TextLogLine>,
+}
+
+impl<R: BufRead> Iterator for TextLogLines<'_, R> {
+    type Item = TextLogLine;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut data = vec![];
+        loop {
+            data.clear();
+            match self.reader.read_until(b'\n', &mut data) {
+                Ok(0) => return self.current.take(),
+                Ok(_) => {}
+                Err(e) => {
+                    warn!("Failed to read text log: {}", e);
+                    return self.current.take();
+                }
+            }
+            let line = String::from_utf8_lossy(&data);
+            let line = line.trim_end_matches('\n').trim_end_matches('\r');
+            let matcher = self.matcher;
+            if let Some(c) = matcher.date_pattern.as_ref().and_then(|p| p.captures(line)) {
+                self.date = Some(c["date"].into());
+            } else if let Some(c) = matcher.pattern.captures(line) {
+                if let Some(parsed) = matcher.parse_line(&c, self.date.as_ref(), line) {
+                    if let Some(last) = self.current.replace(parsed) {
+                        return Some(last);
+                    }
+                }
+            } else if let Some(last) = self.current.as_mut() {
+                // messages may span multiple lines
+                last.text.push('\n');
+                last.text.push_str(line);
+            }
+        }
+    }
+}
+
+impl MsgMatcher for Matcher {
+    fn get_records(&self) -> Option<Records<'_>> {
+        let reader = match File::open(&self.path) {
+            Ok(file) => BufReader::new(file),
+            Err(e) => return Some(Box::new(std::iter::once(Err(e.into())))),
+        };
+        let lines = TextLogLines {
+            matcher: self,
+            reader,
+            date: None,
+            current: None,
+        };
+        Some(Box::new(
+            lines
+                // continuation lines are attached before filtering
+                .filter(move |line| self.range.contains(line.timestamp))
+                .scan(None, move |last, line| {
+                    let record = RecordType::from(Record {
+                        chat_type: self.chat_type.clone(),
+                        owner_id: self.owner.clone(),
+                        group_id: self.chat_id.clone(),
+                        sender_id: line.sender.clone(),
+                        sender_name: line.sender.clone(),
+                        content: line.text.trim_end().into(),
+                        timestamp: line.timestamp,
+                        ..Default::default()
+                    });
+                    // logs only keep minutes, keep messages in the same minute ordered
+                    let near = last
+                        .filter(|t: &i64| *t >= line.timestamp && *t - line.timestamp < 60 * 1000);
+                    let record = modify_timestamp(record, near);
+                    *last = record
+                        .as_ref()
+                        .and_then(|r| r.get_record())
+                        .map(|r| r.timestamp);
+                    Some(record.map(Ok))
+                })
+                .flatten(),
+        ))
+    }
+}
+
+#[test]
+fn test_builtin_profiles() {
+    for (name, line) in [
+        ("line", "12:01\tAlice\tHello"),
+        ("kakao", "January 4, 2023 at 1:01 PM, Alice : Hello"),
+        ("kakao-ko", "2023년 1월 4일 오후 1:01, Alice : Hello"),
+        ("signal", "[2023-01-04 12:01] Alice: Hello"),
+    ] {
+        let profile = TextLogProfile::load(name).unwrap();
+        let c = Regex::new(&profile.pattern)
+            .unwrap()
+            .captures(line)
+            .unwrap();
+        assert_eq!(&c["sender"], "Alice");
+        assert_eq!(&c["text"], "Hello");
+    }
+}
+
+#[cfg(test)]
+fn test_matcher(profile: &str) -> Matcher {
+    let profile = TextLogProfile::load(profile).unwrap();
+    Matcher {
+        owner: "".into(),
+        chat_type: profile.chat_type,
+        chat_id: "chat".into(),
+        path: PathBuf::new(),
+        pattern: Regex::new(&profile.pattern).unwrap(),
+        date_pattern: profile.date_pattern.map(|p| Regex::new(&p).unwrap()),
+        date_format: profile.date_format,
+        offset: FixedOffset::east_opt(0).unwrap(),
+        range: DateRange::default(),
+    }
+}
+
+#[test]
+fn test_text_log_lines() {
+    let matcher = test_matcher("line");
+    let lines = TextLogLines {
+        matcher: &matcher,
+        reader: &b"2023/01/04(Wed)\r\n12:01\tAlice\tHello\r\nsecond line\r\n12:02\tBob\tHi"[..],
+        date: None,
+        current: None,
+    }
+    .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].sender, "Alice");
+    assert_eq!(lines[0].text, "Hello\nsecond line");
+    assert_eq!(lines[0].timestamp, 1672833660000);
+    assert_eq!(lines[1].text, "Hi");
+}
+
+#[test]
+fn test_kakao_ko_lines() {
+    let matcher = test_matcher("kakao-ko");
+    let lines = TextLogLines {
+        matcher: &matcher,
+        reader: "2023년 1월 4일 오전 9:05, 민수 : 안녕\n2023년 1월 4일 오후 1:01, Alice : Hello"
+            .as_bytes(),
+        date: None,
+        current: None,
+    }
+    .collect::<Vec<_>>();
+    assert_eq!(lines.len(), 2);
+    assert_eq!(lines[0].sender, "민수");
+    assert_eq!(lines[0].timestamp, 1672823100000);
+    assert_eq!(lines[1].timestamp, 1672837260000);
+}
@@ -51,6 +51,7 @@ pub struct Extractor {
     owner: String,
     file_name: String,
     attach_getter: Box<dyn QQAttachGetter>,
+    range: DateRange,
 }
 
 impl Extractor {
@@ -63,9 +64,14 @@ impl Extractor {
             owner,
             file_name,
             attach_getter: Box::new(attach_getter),
+            range: DateRange::default(),
         }
     }
 
+    pub fn with_range(self, range: DateRange) -> Self {
+        Self { range, ..self }
+    }
+
     fn get_table(&self) -> Option<Vec<ElementRef>> {
         lazy_static! {
             static ref TABLE_SELECTOR: Selector = Selector::parse("body>table>tbody").unwrap();
@@ -255,11 +261,23 @@ impl Extractor {
         })
     }
 
-    fn transfrom_msg_line(&self, elm: &ElementRef, is_pm: bool) -> Option<QQMsgLine> {
+    /// rows without name and content divs are date lines
+    fn transfrom_date_line(elm: &ElementRef) -> Option<QQMsgLine> {
         lazy_static! {
             static ref DATE_MATCHER: Regex = Regex::new("^日期: (.*?)$").unwrap();
             static ref DIV_SELECTOR: Selector = Selector::parse("tr>td>div").unwrap();
         }
+        (elm.select(&DIV_SELECTOR).take(2).count() < 2)
+            .then(|| {
+                Self::first_match(DATE_MATCHER.captures(&elm.inner_html())).map(QQMsgLine::Date)
+            })
+            .flatten()
+    }
+
+    fn transfrom_msg_line(&self, elm: &ElementRef, is_pm: bool) -> Option<QQMsgLine> {
+        lazy_static! {
+            static ref DIV_SELECTOR: Selector = Selector::parse("tr>td>div").unwrap();
+        }
         let divs = elm.select(&*DIV_SELECTOR).take(2).collect::<Vec<_>>();
         if let [name, content] = *divs.as_slice() {
             self.process_name(name, is_pm)
@@ -272,7 +290,7 @@ impl Extractor {
                     })
                 })
         } else {
-            Self::first_match(DATE_MATCHER.captures(&elm.inner_html())).map(QQMsgLine::Date)
+            None
         }
     }
 
@@ -342,10 +360,9 @@ impl Extractor {
             }
         })
     }
-}
 
-impl MsgMatcher for Extractor {
-    fn get_records(&self) -> Option<Vec<RecordType>> {
+    /// Records of a file are loaded at once, timestamps depend on their neighbours.
+    fn load_records(&self) -> Option<Vec<RecordType<'_>>> {
         self.get_table().and_then(|table| {
             Self::get_group_id(table.iter().take(4).collect::<Vec<_>>()).map(|(is_pm, group_id)| {
                 let group_id = if is_pm || group_id != "0" {
@@ -356,7 +373,19 @@ impl MsgMatcher for Extractor {
                 table
                     .iter()
                     .skip(4)
-                    .map(|elm| self.transfrom_msg_line(elm, is_pm))
+                    .scan(true, |in_range, elm| {
+                        // skip whole days out of range before parsing their messages
+                        let line = Self::transfrom_date_line(elm);
+                        if let Some(QQMsgLine::Date(date)) = &line {
+                            *in_range = NaiveDate::parse_from_str(date, "%Y-%m-%d")
+                                .map_or(true, |date| self.range.contains_day(date));
+                        }
+                        Some(line.or_else(|| {
+                            in_range
+                                .then(|| self.transfrom_msg_line(elm, is_pm))
+                                .flatten()
+                        }))
+                    })
                     .fold(
                         (None, Vec::<RecordType>::new()),
                         |(date, mut ret), curr| match curr {
@@ -365,8 +394,13 @@ impl MsgMatcher for Extractor {
                                 ret,
                             ),
                             Some(line @ QQMsgLine::Message { .. }) => {
-                                self.transfrom_record(group_id.clone(), date, line).map(
-                                    |record_type| {
+                                self.transfrom_record(group_id.clone(), date, line)
+                                    .filter(|record_type| {
+                                        record_type
+                                            .get_record()
+                                            .is_some_and(|r| self.range.contains(r.timestamp))
+                                    })
+                                    .map(|record_type| {
                                         record_type
                                             .get_record()
                                             .and_then(|record| {
@@ -384,8 +418,7 @@ impl MsgMatcher for Extractor {
                                                 )
                                             })
                                             .map(|record| ret.push(record))
-                                    },
-                                );
+                                    });
                                 (date, ret)
                             }
                             None => (date, ret),
@@ -396,3 +429,10 @@ impl MsgMatcher for Extractor {
         })
     }
 }
+
+impl MsgMatcher for Extractor {
+    fn get_records(&self) -> Option<Records<'_>> {
+        self.load_records()
+            .map(|records| records_of(Ok::<_, anyhow::Error>(records)))
+    }
+}
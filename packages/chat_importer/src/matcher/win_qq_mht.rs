@@ -13,6 +13,7 @@ impl Matcher {
         data: &[u8],
         owner: String,
         file_name: String,
+        range: DateRange,
     ) -> Result<Box<dyn MsgMatcher>, MailParseError> {
         info!("Parsing mht...");
         let mht = parse_mail(data)?;
@@ -34,14 +35,37 @@ impl Matcher {
         attaches
             .get("__main__")
             .and_then(|data| String::from_utf8(data.clone()).ok())
-            .map(|html| Extractor::new(html, owner, file_name, AttachGetter::new(attaches.clone())))
+            .map(|html| {
+                Extractor::new(html, owner, file_name, AttachGetter::new(attaches.clone()))
+                    .with_range(range)
+            })
             .map(|qq_html_matcher| Box::new(Self { qq_html_matcher }) as Box<dyn MsgMatcher>)
             .ok_or(MailParseError::Generic("test"))
     }
 }
 
+impl Matcher {
+    /// A chat saved as html instead of mht, its images are not included.
+    pub fn from_html(
+        html: String,
+        owner: String,
+        file_name: String,
+        range: DateRange,
+    ) -> Box<dyn MsgMatcher> {
+        Box::new(Self {
+            qq_html_matcher: Extractor::new(
+                html,
+                owner,
+                file_name,
+                AttachGetter::new(HashMap::new()),
+            )
+            .with_range(range),
+        })
+    }
+}
+
 impl MsgMatcher for Matcher {
-    fn get_records(&self) -> Option<Vec<RecordType>> {
+    fn get_records(&self) -> Option<Records<'_>> {
         self.qq_html_matcher.get_records()
     }
 }
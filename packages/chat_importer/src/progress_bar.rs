@@ -0,0 +1,169 @@
+use gchdb::RecordType;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use lazy_static::lazy_static;
+use log::info;
+use std::collections::HashMap;
+use std::io::IsTerminal;
+use std::time::{Duration, Instant};
+
+/// How often the progress is logged when stdout isn't a terminal.
+const LOG_INTERVAL: Duration = Duration::from_secs(10);
+
+lazy_static! {
+    static ref BARS: MultiProgress = {
+        let bars = MultiProgress::new();
+        if !std::io::stdout().is_terminal() {
+            bars.set_draw_target(ProgressDrawTarget::hidden());
+        }
+        bars
+    };
+}
+
+/// Print a line to stderr above the progress bars instead of through them.
+pub fn eprintln(line: &str) {
+    BARS.suspend(|| eprintln!("{}", line));
+}
+
+fn style(template: &str) -> ProgressStyle {
+    ProgressStyle::with_template(template)
+        .unwrap()
+        .progress_chars("=> ")
+}
+
+/// Progress of a source and of the chat currently imported, with rates and eta.
+///
+/// Totals are the matcher's estimate when it has one, otherwise only counts
+/// and rates are shown.
+pub struct ImportBars {
+    overall: ProgressBar,
+    chat: ProgressBar,
+    /// expected records per group_id
+    totals: HashMap<String, u64>,
+    current: Option<String>,
+    bytes: u64,
+    started: Instant,
+    updated: Instant,
+    tty: bool,
+}
+
+impl ImportBars {
+    pub fn new(name: &str, totals: Option<HashMap<String, u64>>) -> Self {
+        let tty = std::io::stdout().is_terminal();
+        let totals = totals.unwrap_or_default();
+        let overall = BARS.add(match totals.values().sum() {
+            0 => ProgressBar::no_length().with_style(style(
+                "{prefix} {pos} records {per_sec} {msg} [{elapsed_precise}]",
+            )),
+            total => ProgressBar::new(total).with_style(style(
+                "{prefix} [{bar:30}] {pos}/{len} {per_sec} {msg} eta {eta}",
+            )),
+        });
+        overall.set_prefix(name.to_string());
+        let chat = BARS.add(ProgressBar::hidden());
+        Self {
+            overall,
+            chat,
+            totals,
+            current: None,
+            bytes: 0,
+            started: Instant::now(),
+            updated: Instant::now(),
+            tty,
+        }
+    }
+
+    fn switch_chat(&mut self, group_id: &str) {
+        self.chat.finish_and_clear();
+        BARS.remove(&self.chat);
+        self.chat = BARS.add(match self.totals.get(group_id) {
+            Some(&total) if total > 0 => ProgressBar::new(total).with_style(style(
+                "  {prefix} [{bar:30}] {pos}/{len} {per_sec} eta {eta}",
+            )),
+            _ => ProgressBar::no_length().with_style(style("  {prefix} {pos} records {per_sec}")),
+        });
+        self.chat.set_prefix(group_id.to_string());
+        self.current = Some(group_id.into());
+    }
+
+    fn rate(&self) -> f64 {
+        self.bytes as f64 / 1024.0 / 1024.0 / self.started.elapsed().as_secs_f64().max(0.001)
+    }
+
+    pub fn record(&mut self, record_type: &RecordType) {
+        if let Some(record) = record_type.get_record() {
+            if self.current.as_deref() != Some(record.group_id.as_str()) {
+                self.switch_chat(&record.group_id);
+            }
+        }
+        self.bytes += record_type
+            .get_attaches()
+            .map_or(0, |a| a.values().map(|data| data.len() as u64).sum());
+        self.overall.inc(1);
+        self.chat.inc(1);
+        self.tick();
+    }
+
+    /// Count a record that failed to transform.
+    pub fn failed(&mut self) {
+        self.overall.inc(1);
+        self.chat.inc(1);
+        self.tick();
+    }
+
+    fn tick(&mut self) {
+        let interval = if self.tty {
+            Duration::from_millis(500)
+        } else {
+            LOG_INTERVAL
+        };
+        if self.updated.elapsed() < interval {
+            return;
+        }
+        self.updated = Instant::now();
+        if self.tty {
+            self.overall
+                .set_message(format!("{:.2} MB/s attachments", self.rate()));
+        } else {
+            self.log();
+        }
+    }
+
+    fn log(&self) {
+        let elapsed = self.started.elapsed().as_secs_f64().max(0.001);
+        let records = self.overall.position();
+        let eta = self
+            .overall
+            .length()
+            .filter(|&len| len > records)
+            .map(|len| {
+                Duration::from_secs_f64((len - records) as f64 * elapsed / records.max(1) as f64)
+            });
+        info!(
+            "{}: {}{} records, {:.1} records/s, {:.2} MB/s attachments{}{}",
+            self.overall.prefix(),
+            records,
+            self.overall
+                .length()
+                .map(|len| format!("/{}", len))
+                .unwrap_or_default(),
+            records as f64 / elapsed,
+            self.rate(),
+            self.current
+                .as_ref()
+                .map(|chat| format!(", chat {} at {}", chat, self.chat.position()))
+                .unwrap_or_default(),
+            eta.map(|eta| format!(", eta {}s", eta.as_secs()))
+                .unwrap_or_default(),
+        );
+    }
+
+    pub fn finish(self) {
+        if !self.tty {
+            self.log();
+        }
+        self.chat.finish_and_clear();
+        self.overall.finish_and_clear();
+        BARS.remove(&self.chat);
+        BARS.remove(&self.overall);
+    }
+}
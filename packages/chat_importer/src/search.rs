@@ -0,0 +1,201 @@
+use crate::export::{display, format_time};
+use crate::store::{ChatKey, Search, Store, StoredRecord};
+use anyhow::Result;
+use gchdb::{ChatRecorder, SqliteChatRecorder};
+use log::warn;
+use serde::Serialize;
+use std::path::Path;
+
+/// Ids read from the search index at a time.
+const INDEX_PAGE: usize = 1000;
+
+/// Terms of a query, all of which must match.
+#[derive(Debug, PartialEq)]
+pub struct Clause {
+    /// any of them matches
+    pub terms: Vec<String>,
+    pub negated: bool,
+}
+
+/// A search query: words and `"quoted phrases"` must all appear in a message,
+/// `a OR b` matches either and `-word` excludes messages containing it.
+/// Chinese text is split into words by the cang-jie tokenizer of the index.
+#[derive(Debug, Default, PartialEq)]
+pub struct SearchQuery {
+    pub clauses: Vec<Clause>,
+}
+
+impl SearchQuery {
+    pub fn parse(query: &str) -> Result<Self> {
+        // (term, negated, quoted)
+        let mut tokens = vec![];
+        let mut chars = query.chars().peekable();
+        while let Some(&c) = chars.peek() {
+            if c.is_whitespace() {
+                chars.next();
+                continue;
+            }
+            let negated = c == '-';
+            if negated {
+                chars.next();
+            }
+            if chars.peek() == Some(&'"') {
+                chars.next();
+                let phrase = chars.by_ref().take_while(|&c| c != '"').collect::<String>();
+                tokens.push((phrase, negated, true));
+            } else {
+                let mut word = String::new();
+                while let Some(c) = chars.next_if(|c| !c.is_whitespace()) {
+                    word.push(c);
+                }
+                tokens.push((word, negated, false));
+            }
+        }
+        let mut clauses: Vec<Clause> = vec![];
+        let mut or = false;
+        for (term, negated, quoted) in tokens {
+            if term == "OR" && !quoted && !negated {
+                or = true;
+                continue;
+            }
+            if term.is_empty() {
+                continue;
+            }
+            match clauses.last_mut() {
+                Some(last) if or && !last.negated && !negated => last.terms.push(term),
+                _ => clauses.push(Clause {
+                    terms: vec![term],
+                    negated,
+                }),
+            }
+            or = false;
+        }
+        if clauses.iter().all(|clause| clause.negated) {
+            return Err(anyhow::anyhow!("Nothing to search for in: {}", query));
+        }
+        Ok(Self { clauses })
+    }
+
+    /// The query in tantivy's syntax, every term quoted so it is taken literally.
+    pub fn to_index_query(&self) -> String {
+        self.clauses
+            .iter()
+            .map(|clause| {
+                let terms = clause
+                    .terms
+                    .iter()
+                    .map(|term| format!("\"{}\"", term.replace(['"', '\\'], " ")))
+                    .collect::<Vec<_>>();
+                let sign = if clause.negated { '-' } else { '+' };
+                match terms.as_slice() {
+                    [term] => format!("{}{}", sign, term),
+                    terms => format!("{}({})", sign, terms.join(" ")),
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+}
+
+/// Ids of every record the search index finds for the query.
+fn index_candidates(db: &Path, query: &SearchQuery) -> Result<Vec<i64>> {
+    let recorder = SqliteChatRecorder::new(db.to_string_lossy().as_ref())?;
+    let query = query.to_index_query();
+    let mut ids = vec![];
+    loop {
+        let page = recorder.search(&query, ids.len(), INDEX_PAGE)?;
+        let done = page.len() < INDEX_PAGE;
+        ids.extend(page.into_iter().map(i64::from));
+        if done {
+            return Ok(ids);
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct Hit {
+    chat: ChatKey,
+    time: String,
+    record: StoredRecord,
+    before: Vec<StoredRecord>,
+    after: Vec<StoredRecord>,
+}
+
+fn line(mark: char, record: &StoredRecord) -> String {
+    format!(
+        "{} {} {}: {}",
+        mark,
+        format_time(record.timestamp),
+        record.sender(),
+        record.content.replace('\n', "\n    ")
+    )
+}
+
+/// Print the records matching a search with `context` records before and after each.
+pub fn search(db: &Path, search: &Search, context: usize, json: bool) -> Result<()> {
+    let candidates = index_candidates(db, &search.query)?;
+    let store = Store::open(db)?;
+    let hits = store
+        .search(search, &candidates)?
+        .into_iter()
+        .map(|(chat, record)| {
+            let (before, after) = store.around(&chat, &record, context, context)?;
+            Ok(Hit {
+                time: format_time(record.timestamp),
+                chat,
+                record,
+                before,
+                after,
+            })
+        })
+        .collect::<Result<Vec<_>>>()?;
+    if json {
+        println!("{}", serde_json::to_string_pretty(&hits)?);
+        return Ok(());
+    }
+    for (i, hit) in hits.iter().enumerate() {
+        if i > 0 && context > 0 {
+            println!("--");
+        }
+        if context > 0 || i == 0 || hits[i - 1].chat != hit.chat {
+            println!("{}", display(&hit.chat));
+        }
+        for record in &hit.before {
+            println!("{}", line(' ', record));
+        }
+        println!("{}", line('>', &hit.record));
+        for record in &hit.after {
+            println!("{}", line(' ', record));
+        }
+    }
+    if hits.len() == search.limit {
+        warn!("Stopped at {} results, see --limit", search.limit);
+    }
+    Ok(())
+}
+
+#[test]
+fn test_search_query() {
+    let clause = |terms: &[&str], negated| Clause {
+        terms: terms.iter().map(|t| t.to_string()).collect(),
+        negated,
+    };
+    assert_eq!(
+        SearchQuery::parse(r#"dinner "next week" 周五 OR friday -cancel"#).unwrap(),
+        SearchQuery {
+            clauses: vec![
+                clause(&["dinner"], false),
+                clause(&["next week"], false),
+                clause(&["周五", "friday"], false),
+                clause(&["cancel"], true),
+            ]
+        }
+    );
+    assert_eq!(
+        SearchQuery::parse(r#"dinner "next week" 周五 OR friday -cancel"#)
+            .unwrap()
+            .to_index_query(),
+        r#"+"dinner" +"next week" +("周五" "friday") -"cancel""#
+    );
+    assert!(SearchQuery::parse("-spam").is_err());
+}
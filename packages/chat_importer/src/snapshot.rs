@@ -0,0 +1,232 @@
+use anyhow::Result;
+use gchdb::Record;
+use ibackuptool2::Backup;
+use log::{info, warn};
+use rusqlite::{params, Connection};
+use std::cell::RefCell;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// One backup of a device, imported as part of a snapshot series.
+pub struct Snapshot {
+    pub path: PathBuf,
+    pub device_id: String,
+    pub date: String,
+}
+
+impl Snapshot {
+    /// Read device id and backup date of every backup, oldest first.
+    /// All backups must come from the same device.
+    pub fn sort(paths: Vec<PathBuf>) -> Result<Vec<Self>> {
+        let mut snapshots = paths
+            .into_iter()
+            .map(|path| {
+                let backup = Backup::new(&path).map_err(|e| {
+                    anyhow::anyhow!("Snapshots need itunes backups: {}, {}", path.display(), e)
+                })?;
+                Ok(Self {
+                    device_id: backup.manifest.lockdown.unique_device_id.clone(),
+                    // iso 8601 dates, sorting as strings keeps them in time order
+                    date: backup.status.date.clone(),
+                    path,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+        if let Some(first) = snapshots.first() {
+            if let Some(other) = snapshots.iter().find(|s| s.device_id != first.device_id) {
+                return Err(anyhow::anyhow!(
+                    "Snapshots come from different devices: {} is {}, {} is {}",
+                    first.path.display(),
+                    first.device_id,
+                    other.path.display(),
+                    other.device_id
+                ));
+            }
+        }
+        snapshots.sort_by(|a, b| a.date.cmp(&b.date));
+        Ok(snapshots)
+    }
+}
+
+/// (chat_type, owner_id, group_id, sender_id, timestamp)
+type RecordKey = (String, String, String, String, i64);
+
+/// Tracks in which snapshots each record was seen, next to the records in `record.db`.
+///
+/// `first_seen` / `last_seen` hold snapshot dates, a record whose `last_seen`
+/// is older than the newest snapshot of its device was deleted on the phone.
+pub struct SnapshotLog {
+    conn: Connection,
+    current: Option<(String, String)>,
+    /// seen in the current batch
+    pending: RefCell<Vec<RecordKey>>,
+}
+
+impl SnapshotLog {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open(path)?;
+        conn.busy_timeout(Duration::from_secs(30))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS snapshots (
+                id INTEGER PRIMARY KEY AUTOINCREMENT,
+                device_id TEXT NOT NULL,
+                date TEXT NOT NULL,
+                path TEXT NOT NULL,
+                UNIQUE (device_id, date)
+            );
+            CREATE TABLE IF NOT EXISTS record_snapshots (
+                chat_type TEXT NOT NULL,
+                owner_id TEXT NOT NULL,
+                group_id TEXT NOT NULL,
+                sender_id TEXT NOT NULL,
+                timestamp INTEGER NOT NULL,
+                device_id TEXT NOT NULL,
+                first_seen TEXT NOT NULL,
+                last_seen TEXT NOT NULL,
+                PRIMARY KEY (chat_type, owner_id, group_id, sender_id, timestamp)
+            );",
+        )?;
+        Ok(Self {
+            conn,
+            current: None,
+            pending: RefCell::new(vec![]),
+        })
+    }
+
+    /// Start importing a snapshot, later `seen` records are attributed to it.
+    pub fn begin(&mut self, snapshot: &Snapshot) -> Result<()> {
+        self.conn.execute(
+            "INSERT INTO snapshots (device_id, date, path) VALUES (?1, ?2, ?3)
+            ON CONFLICT (device_id, date) DO UPDATE SET path = excluded.path",
+            params![
+                snapshot.device_id,
+                snapshot.date,
+                snapshot.path.to_string_lossy()
+            ],
+        )?;
+        self.current = Some((snapshot.device_id.clone(), snapshot.date.clone()));
+        info!(
+            "Importing snapshot {}: {}",
+            snapshot.date,
+            snapshot.path.display()
+        );
+        Ok(())
+    }
+
+    /// Remember a record of the current snapshot, written by the next `commit`.
+    pub fn seen(&self, record: &Record) {
+        if self.current.is_some() {
+            self.pending.borrow_mut().push((
+                record.chat_type.clone(),
+                record.owner_id.clone(),
+                record.group_id.clone(),
+                record.sender_id.clone(),
+                record.timestamp,
+            ));
+        }
+    }
+
+    /// Write the records seen since the last commit in one transaction.
+    pub fn commit(&self) -> Result<()> {
+        let (device_id, date) = match &self.current {
+            Some(current) => current,
+            None => return Ok(()),
+        };
+        let tx = self.conn.unchecked_transaction()?;
+        {
+            // an older backup may still be imported after a newer one later on
+            let mut stmt = tx.prepare_cached(
+                "INSERT INTO record_snapshots
+                (chat_type, owner_id, group_id, sender_id, timestamp, device_id, first_seen, last_seen)
+                VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?7)
+                ON CONFLICT (chat_type, owner_id, group_id, sender_id, timestamp) DO UPDATE SET
+                first_seen = min(first_seen, excluded.first_seen),
+                last_seen = max(last_seen, excluded.last_seen)",
+            )?;
+            for (chat_type, owner_id, group_id, sender_id, timestamp) in
+                self.pending.borrow_mut().drain(..)
+            {
+                stmt.execute(params![
+                    chat_type, owner_id, group_id, sender_id, timestamp, device_id, date
+                ])?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    /// Log how many records are missing from the newest snapshot of the imported device.
+    pub fn report(&self) -> Result<()> {
+        let device_id = match &self.current {
+            Some((device_id, _)) => device_id,
+            None => return Ok(()),
+        };
+        let date: String = self.conn.query_row(
+            "SELECT max(date) FROM snapshots WHERE device_id = ?1",
+            params![device_id],
+            |row| row.get(0),
+        )?;
+        let mut stmt = self.conn.prepare(
+            "SELECT chat_type, group_id, count(*) FROM record_snapshots
+            WHERE device_id = ?1 AND last_seen != ?2
+            GROUP BY chat_type, group_id",
+        )?;
+        let deleted = stmt
+            .query_map(params![device_id, date], |row| {
+                Ok((
+                    row.get::<_, String>(0)?,
+                    row.get::<_, String>(1)?,
+                    row.get::<_, i64>(2)?,
+                ))
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        for (chat_type, group_id, count) in deleted.iter() {
+            warn!(
+                "{} {}: {} records missing in snapshot {}",
+                chat_type, group_id, count, date
+            );
+        }
+        info!(
+            "{} records of {} only found in older snapshots",
+            deleted.iter().map(|(_, _, count)| count).sum::<i64>(),
+            device_id
+        );
+        Ok(())
+    }
+}
+
+#[test]
+fn test_snapshot_log() {
+    let dir = tempfile::tempdir().unwrap();
+    let mut log = SnapshotLog::open(dir.path().join("record.db")).unwrap();
+    let record = |timestamp| Record {
+        chat_type: "SMS".into(),
+        sender_id: "o'brien".into(),
+        timestamp,
+        ..Default::default()
+    };
+    for (date, timestamps) in [("2020-01-01", vec![1, 2]), ("2021-01-01", vec![2, 3])] {
+        log.begin(&Snapshot {
+            path: PathBuf::from(date),
+            device_id: "udid".into(),
+            date: date.into(),
+        })
+        .unwrap();
+        for timestamp in timestamps {
+            log.seen(&record(timestamp));
+        }
+        log.commit().unwrap();
+    }
+    let seen = |timestamp: i64| -> (String, String) {
+        log.conn
+            .query_row(
+                "SELECT first_seen, last_seen FROM record_snapshots WHERE timestamp = ?1",
+                params![timestamp],
+                |row| Ok((row.get(0)?, row.get(1)?)),
+            )
+            .unwrap()
+    };
+    assert_eq!(seen(1), ("2020-01-01".into(), "2020-01-01".into()));
+    assert_eq!(seen(2), ("2020-01-01".into(), "2021-01-01".into()));
+    assert_eq!(seen(3), ("2021-01-01".into(), "2021-01-01".into()));
+}
@@ -0,0 +1,373 @@
+use crate::matcher::DateRange;
+use crate::search::SearchQuery;
+use anyhow::Result;
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension, Row};
+use serde::Serialize;
+use serde_json::Value;
+
+/// Selects chats by any of their keys, unset keys match everything.
+#[derive(Clone, Debug, Default)]
+pub struct ChatQuery {
+    pub chat_type: Option<String>,
+    pub owner_id: Option<String>,
+    pub group_id: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+pub struct ChatKey {
+    pub chat_type: String,
+    pub owner_id: String,
+    pub group_id: String,
+}
+
+impl ChatKey {
+    /// Folder or file name of the chat, safe on every file system.
+    pub fn file_name(&self) -> String {
+        [&self.chat_type, &self.owner_id, &self.group_id]
+            .iter()
+            .map(|part| {
+                part.chars()
+                    .map(|c| match c {
+                        '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '_',
+                        c if c.is_control() => '_',
+                        c => c,
+                    })
+                    .collect::<String>()
+            })
+            .collect::<Vec<_>>()
+            .join("-")
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+pub struct StoredRecord {
+    pub id: i64,
+    pub sender_id: String,
+    pub sender_name: String,
+    pub content: String,
+    pub timestamp: i64,
+    pub metadata: Option<Value>,
+    /// (name, blob hash)
+    pub attachments: Vec<(String, i64)>,
+}
+
+impl StoredRecord {
+    /// The sender's name, or its id when the name is unknown.
+    pub fn sender(&self) -> &str {
+        if self.sender_name.is_empty() {
+            &self.sender_id
+        } else {
+            &self.sender_name
+        }
+    }
+
+    /// A text value of the metadata, e.g. the title of a WeChat app message.
+    pub fn tag(&self, key: &str) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|m| m.get("hash"))
+            .and_then(|hash| hash.get(key))
+            .and_then(|v| v.as_str())
+            .filter(|v| !v.is_empty())
+    }
+
+    /// The WeChat message type in the metadata, e.g. `CustomApp` for app messages.
+    pub fn mtype(&self) -> Option<&str> {
+        self.metadata
+            .as_ref()
+            .and_then(|m| m.get("mtype"))
+            .and_then(|v| v.as_str())
+    }
+
+    /// Blobs of the record: its attachments, and those WeChat metadata refers to by hash.
+    pub fn blobs(&self) -> Vec<(String, i64)> {
+        let mut blobs = self.attachments.clone();
+        if let Some(hash) = self
+            .metadata
+            .as_ref()
+            .and_then(|m| m.get("hash"))
+            .and_then(|h| h.as_object())
+        {
+            for (name, value) in hash {
+                if let Some(hash) = value.as_i64().filter(|&h| h != 0) {
+                    if !blobs.iter().any(|(_, h)| *h == hash) {
+                        blobs.push((name.clone(), hash));
+                    }
+                }
+            }
+        }
+        blobs
+    }
+}
+
+const RECORD_COLUMNS: &str = "id, sender_id, sender_name, content, timestamp, metadata";
+
+/// A record of `RECORD_COLUMNS`, starting at column `first`.
+fn stored_record(row: &Row, first: usize) -> rusqlite::Result<StoredRecord> {
+    Ok(StoredRecord {
+        id: row.get(first)?,
+        sender_id: row.get(first + 1)?,
+        sender_name: row.get(first + 2)?,
+        content: row.get(first + 3)?,
+        timestamp: row.get(first + 4)?,
+        metadata: row
+            .get::<_, Option<Vec<u8>>>(first + 5)?
+            .and_then(|m| serde_json::from_slice(&m).ok()),
+        attachments: vec![],
+    })
+}
+
+/// Filters of a search, see `SearchQuery` for the query syntax.
+pub struct Search {
+    pub query: SearchQuery,
+    pub chat: ChatQuery,
+    /// sender id or name
+    pub sender: Option<String>,
+    pub range: DateRange,
+    pub has_attachment: bool,
+    pub limit: usize,
+}
+
+/// Where the records of a chat were imported from, as kept by the import journal.
+#[derive(Clone, Debug, Serialize)]
+pub struct Provenance {
+    pub matcher: String,
+    pub source: String,
+    pub backup_uuid: Option<String>,
+    pub device: Option<String>,
+    /// app account of the chat owner, for sources holding several
+    pub account: Option<String>,
+    pub nickname: Option<String>,
+    pub imported_at: String,
+}
+
+/// Read access to the records of `record.db`, for exports.
+pub struct Store {
+    conn: Connection,
+    has_attachments: bool,
+    has_provenance: bool,
+}
+
+fn has_table(conn: &Connection, name: &str) -> Result<bool> {
+    Ok(conn
+        .query_row(
+            "SELECT 1 FROM sqlite_master WHERE type = 'table' AND name = ?1",
+            params![name],
+            |_| Ok(()),
+        )
+        .optional()?
+        .is_some())
+}
+
+impl Store {
+    pub fn open<P: AsRef<std::path::Path>>(path: P) -> Result<Self> {
+        let conn = Connection::open_with_flags(
+            path,
+            OpenFlags::SQLITE_OPEN_READ_ONLY
+                | OpenFlags::SQLITE_OPEN_URI
+                | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+        )?;
+        // databases of older versions lack these
+        let has_attachments = has_table(&conn, "attachments")?;
+        let has_provenance = has_table(&conn, "import_journal")?
+            && has_table(&conn, "import_provenance")?
+            && has_table(&conn, "import_accounts")?;
+        Ok(Self {
+            conn,
+            has_attachments,
+            has_provenance,
+        })
+    }
+
+    /// Chats matching the query with their record count.
+    pub fn chats(&self, query: &ChatQuery) -> Result<Vec<(ChatKey, i64)>> {
+        let mut stmt = self.conn.prepare(
+            "SELECT chat_type, owner_id, group_id, count(*) FROM records
+            WHERE (?1 IS NULL OR chat_type = ?1)
+            AND (?2 IS NULL OR owner_id = ?2)
+            AND (?3 IS NULL OR group_id = ?3)
+            GROUP BY chat_type, owner_id, group_id
+            ORDER BY chat_type, owner_id, group_id",
+        )?;
+        let chats = stmt
+            .query_map(
+                params![query.chat_type, query.owner_id, query.group_id],
+                |row| {
+                    Ok((
+                        ChatKey {
+                            chat_type: row.get(0)?,
+                            owner_id: row.get(1)?,
+                            group_id: row.get(2)?,
+                        },
+                        row.get(3)?,
+                    ))
+                },
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(chats)
+    }
+
+    /// Records of a chat in time order.
+    pub fn records(&self, chat: &ChatKey, range: DateRange) -> Result<Vec<StoredRecord>> {
+        let (since, until) = range.bounds();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT {} FROM records
+            WHERE chat_type = ?1 AND owner_id = ?2 AND group_id = ?3
+            AND timestamp >= ?4 AND timestamp <= ?5
+            ORDER BY timestamp, id",
+            RECORD_COLUMNS
+        ))?;
+        let mut records = stmt
+            .query_map(
+                params![chat.chat_type, chat.owner_id, chat.group_id, since, until],
+                |row| stored_record(row, 0),
+            )?
+            .collect::<Result<Vec<_>, _>>()?;
+        self.load_attachments(&mut records)?;
+        Ok(records)
+    }
+
+    fn load_attachments(&self, records: &mut [StoredRecord]) -> Result<()> {
+        if self.has_attachments {
+            let mut stmt = self
+                .conn
+                .prepare("SELECT name, hash FROM attachments WHERE record_id = ?1")?;
+            for record in records.iter_mut() {
+                record.attachments = stmt
+                    .query_map(params![record.id], |row| Ok((row.get(0)?, row.get(1)?)))?
+                    .collect::<Result<Vec<_>, _>>()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Up to `before` and `after` records of the chat around a record.
+    pub fn around(
+        &self,
+        chat: &ChatKey,
+        record: &StoredRecord,
+        before: usize,
+        after: usize,
+    ) -> Result<(Vec<StoredRecord>, Vec<StoredRecord>)> {
+        let load = |condition: &str, limit: usize| -> Result<Vec<StoredRecord>> {
+            let mut stmt = self.conn.prepare(&format!(
+                "SELECT {} FROM records
+                WHERE chat_type = ?1 AND owner_id = ?2 AND group_id = ?3 AND {} LIMIT ?6",
+                RECORD_COLUMNS, condition
+            ))?;
+            let mut records = stmt
+                .query_map(
+                    params![
+                        chat.chat_type,
+                        chat.owner_id,
+                        chat.group_id,
+                        record.timestamp,
+                        record.id,
+                        limit as i64
+                    ],
+                    |row| stored_record(row, 0),
+                )?
+                .collect::<Result<Vec<_>, _>>()?;
+            self.load_attachments(&mut records)?;
+            Ok(records)
+        };
+        let mut earlier = load(
+            "(timestamp, id) < (?4, ?5) ORDER BY timestamp DESC, id DESC",
+            before,
+        )?;
+        earlier.reverse();
+        let later = load("(timestamp, id) > (?4, ?5) ORDER BY timestamp, id", after)?;
+        Ok((earlier, later))
+    }
+
+    /// Records among the `candidates` ids of the search index that pass the filters
+    /// of a search, newest first.
+    pub fn search(
+        &self,
+        search: &Search,
+        candidates: &[i64],
+    ) -> Result<Vec<(ChatKey, StoredRecord)>> {
+        let (since, until) = search.range.bounds();
+        let mut stmt = self.conn.prepare(&format!(
+            "SELECT chat_type, owner_id, group_id, {} FROM records
+            WHERE id IN (SELECT value FROM json_each(?1))
+            AND (?2 IS NULL OR chat_type = ?2)
+            AND (?3 IS NULL OR owner_id = ?3)
+            AND (?4 IS NULL OR group_id = ?4)
+            AND (?5 IS NULL OR sender_id = ?5 OR sender_name = ?5)
+            AND timestamp >= ?6 AND timestamp <= ?7
+            ORDER BY timestamp DESC, id DESC",
+            RECORD_COLUMNS
+        ))?;
+        let mut rows = stmt.query(params![
+            serde_json::to_string(candidates)?,
+            search.chat.chat_type,
+            search.chat.owner_id,
+            search.chat.group_id,
+            search.sender,
+            since,
+            until
+        ])?;
+        let mut hits = vec![];
+        while let Some(row) = rows.next()? {
+            let mut record = vec![stored_record(row, 3)?];
+            self.load_attachments(&mut record)?;
+            let record = record.remove(0);
+            if search.has_attachment && record.blobs().is_empty() {
+                continue;
+            }
+            let chat = ChatKey {
+                chat_type: row.get(0)?,
+                owner_id: row.get(1)?,
+                group_id: row.get(2)?,
+            };
+            hits.push((chat, record));
+            if hits.len() >= search.limit {
+                break;
+            }
+        }
+        Ok(hits)
+    }
+
+    /// Sources the chat was imported from, oldest import first.
+    pub fn provenance(&self, chat: &ChatKey) -> Result<Vec<Provenance>> {
+        if !self.has_provenance {
+            return Ok(vec![]);
+        }
+        let mut stmt = self.conn.prepare(
+            "SELECT DISTINCT p.matcher, p.source, p.backup_uuid, p.device,
+                a.account, a.nickname, p.imported_at
+            FROM import_journal j JOIN import_provenance p
+            ON p.matcher = j.matcher AND p.source = j.source
+            LEFT JOIN import_accounts a
+            ON a.matcher = j.matcher AND a.source = j.source AND a.owner_id = j.owner_id
+            WHERE j.owner_id = ?1 AND j.chat_id = ?2
+            ORDER BY p.imported_at",
+        )?;
+        let sources = stmt
+            .query_map(params![chat.owner_id, chat.group_id], |row| {
+                Ok(Provenance {
+                    matcher: row.get(0)?,
+                    source: row.get(1)?,
+                    backup_uuid: row.get(2)?,
+                    device: row.get(3)?,
+                    account: row.get(4)?,
+                    nickname: row.get(5)?,
+                    imported_at: row.get(6)?,
+                })
+            })?
+            .collect::<Result<Vec<_>, _>>()?;
+        Ok(sources)
+    }
+
+    pub fn blob(&self, hash: i64) -> Result<Option<Vec<u8>>> {
+        Ok(self
+            .conn
+            .query_row(
+                "SELECT blob FROM blobs WHERE hash = ?1",
+                params![hash],
+                |row| row.get(0),
+            )
+            .optional()?)
+    }
+}
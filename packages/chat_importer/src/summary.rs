@@ -0,0 +1,188 @@
+use anyhow::Result;
+use gchdb::{Record, RecordType};
+use rusqlite::{params, Connection, OpenFlags, OptionalExtension};
+use serde::Serialize;
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+use std::fmt::{Display, Formatter};
+use std::fs::write;
+use std::path::Path;
+
+/// Something a matcher had to skip while transforming a record.
+#[derive(Clone, Debug)]
+pub enum Issue {
+    MissingAttachment,
+    UnknownType(String),
+}
+
+/// Error context naming the chat (`group_id`) a failed record belongs to.
+#[derive(Debug)]
+pub struct InChat(pub String);
+
+impl Display for InChat {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "in chat {}", self.0)
+    }
+}
+
+/// Metadata of the record already in `record.db` with the same key,
+/// `Some(None)` when it exists without metadata.
+pub fn existing_metadata(conn: &Connection, record: &Record) -> Result<Option<Option<Vec<u8>>>> {
+    // same key as insert_or_update_record uses to find an existing record
+    Ok(conn
+        .query_row(
+            "SELECT metadata FROM records
+            WHERE chat_type = ?1 AND owner_id = ?2 AND group_id = ?3
+            AND sender_id = ?4 AND timestamp = ?5",
+            params![
+                record.chat_type,
+                record.owner_id,
+                record.group_id,
+                record.sender_id,
+                record.timestamp
+            ],
+            |row| row.get(0),
+        )
+        .optional()?)
+}
+
+pub enum Change {
+    New,
+    Updated,
+    Unchanged,
+}
+
+#[derive(Default, Serialize)]
+pub struct ChatSummary {
+    pub chat_type: String,
+    pub read: usize,
+    pub inserted: usize,
+    pub updated: usize,
+    pub unchanged: usize,
+    pub failed: usize,
+    pub attachments: usize,
+    pub missing_attachments: usize,
+    pub unknown_types: BTreeMap<String, usize>,
+    pub errors: Vec<String>,
+}
+
+/// Per chat counts of an import, printed as a table at the end.
+pub struct Summary {
+    conn: Connection,
+    chats: RefCell<BTreeMap<String, ChatSummary>>,
+}
+
+impl Summary {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(Self {
+            conn: Connection::open_with_flags(
+                path,
+                OpenFlags::SQLITE_OPEN_READ_ONLY
+                    | OpenFlags::SQLITE_OPEN_URI
+                    | OpenFlags::SQLITE_OPEN_NO_MUTEX,
+            )?,
+            chats: RefCell::new(BTreeMap::new()),
+        })
+    }
+
+    /// What writing the record will change, checked before it is written.
+    pub fn classify(&self, record_type: &RecordType) -> Result<Change> {
+        let record = match record_type.get_record() {
+            Some(record) => record,
+            None => return Ok(Change::Unchanged),
+        };
+        Ok(match existing_metadata(&self.conn, record)? {
+            None => Change::New,
+            Some(old) if record.metadata.is_some() && old != record.metadata => Change::Updated,
+            Some(_) => Change::Unchanged,
+        })
+    }
+
+    pub fn written(&self, record_type: &RecordType, change: Change, ok: bool) {
+        let record = match record_type.get_record() {
+            Some(record) => record,
+            None => return,
+        };
+        let mut chats = self.chats.borrow_mut();
+        let chat = chats.entry(record.group_id.clone()).or_default();
+        chat.chat_type = record.chat_type.clone();
+        chat.read += 1;
+        chat.attachments += record_type.get_attaches().map_or(0, |a| a.len());
+        match (ok, change) {
+            (false, _) => chat.failed += 1,
+            (true, Change::New) => chat.inserted += 1,
+            (true, Change::Updated) => chat.updated += 1,
+            (true, Change::Unchanged) => chat.unchanged += 1,
+        }
+    }
+
+    pub fn failed(&self, error: &anyhow::Error) {
+        let chat = error
+            .downcast_ref::<InChat>()
+            .map(|c| c.0.clone())
+            .unwrap_or_default();
+        let mut chats = self.chats.borrow_mut();
+        let chat = chats.entry(chat).or_default();
+        chat.failed += 1;
+        chat.errors.push(format!("{:#}", error));
+    }
+
+    pub fn issues(&self, issues: Vec<(String, Issue)>) {
+        let mut chats = self.chats.borrow_mut();
+        for (chat, issue) in issues {
+            let chat = chats.entry(chat).or_default();
+            match issue {
+                Issue::MissingAttachment => chat.missing_attachments += 1,
+                Issue::UnknownType(t) => *chat.unknown_types.entry(t).or_default() += 1,
+            }
+        }
+    }
+
+    /// Print the summary table, and write it as json when a path is given.
+    pub fn report<P: AsRef<Path>>(&self, path: Option<P>) -> Result<()> {
+        let chats = self.chats.borrow();
+        let width = chats
+            .keys()
+            .map(|chat| chat.chars().count())
+            .chain(std::iter::once(4))
+            .max()
+            .unwrap_or_default();
+        println!(
+            "{:width$} {:>8} {:>7} {:>8} {:>7} {:>9} {:>6} {:>6} {:>7}  unknown types",
+            "chat",
+            "type",
+            "read",
+            "inserted",
+            "updated",
+            "unchanged",
+            "failed",
+            "attach",
+            "missing",
+            width = width
+        );
+        for (name, chat) in chats.iter() {
+            println!(
+                "{:width$} {:>8} {:>7} {:>8} {:>7} {:>9} {:>6} {:>6} {:>7}  {}",
+                if name.is_empty() { "-" } else { name },
+                chat.chat_type,
+                chat.read,
+                chat.inserted,
+                chat.updated,
+                chat.unchanged,
+                chat.failed,
+                chat.attachments,
+                chat.missing_attachments,
+                chat.unknown_types
+                    .iter()
+                    .map(|(t, count)| format!("{}x{}", t, count))
+                    .collect::<Vec<_>>()
+                    .join(","),
+                width = width
+            );
+        }
+        if let Some(path) = path {
+            write(path, serde_json::to_vec_pretty(&*chats)?)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,202 @@
+use super::*;
+use std::collections::HashMap;
+
+const MBDB_MAGIC: &[u8] = b"mbdb";
+const MBDX_MAGIC: &[u8] = b"mbdx";
+const HEADER_LEN: usize = 6;
+
+/// Cursor over the big endian records of `Manifest.mbdb` / `Manifest.mbdx`.
+struct MbdbReader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> MbdbReader<'a> {
+    fn take(&mut self, len: usize) -> Result<&'a [u8], Box<dyn std::error::Error>> {
+        let end = self.offset + len;
+        if end > self.data.len() {
+            return Err(format!("unexpected end of mbdb at offset {}", self.offset).into());
+        }
+        let slice = &self.data[self.offset..end];
+        self.offset = end;
+        Ok(slice)
+    }
+
+    fn read_u8(&mut self) -> Result<u8, Box<dyn std::error::Error>> {
+        Ok(self.take(1)?[0])
+    }
+
+    fn read_u16(&mut self) -> Result<u16, Box<dyn std::error::Error>> {
+        let data = self.take(2)?;
+        Ok(u16::from_be_bytes([data[0], data[1]]))
+    }
+
+    fn read_u32(&mut self) -> Result<u32, Box<dyn std::error::Error>> {
+        Ok(as_u32_be(self.take(4)?))
+    }
+
+    fn read_u64(&mut self) -> Result<u64, Box<dyn std::error::Error>> {
+        Ok(((self.read_u32()? as u64) << 32) + self.read_u32()? as u64)
+    }
+
+    /// Length prefixed, 0xFFFF marks an absent value.
+    fn read_bytes(&mut self) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        match self.read_u16()? {
+            0xFFFF => Ok(vec![]),
+            len => Ok(self.take(len as usize)?.to_vec()),
+        }
+    }
+
+    fn read_string(&mut self) -> Result<String, Box<dyn std::error::Error>> {
+        Ok(String::from_utf8_lossy(&self.read_bytes()?).into_owned())
+    }
+}
+
+/// Parse the file list of a legacy (iOS 4-9) backup.
+///
+/// `mbdx` is the separate index of iOS 4 backups, mapping record offsets to
+/// file ids; newer backups derive the id as `sha1("domain-path")`.
+pub fn parse_mbdb(
+    mbdb: &[u8],
+    mbdx: Option<&[u8]>,
+) -> Result<Vec<BackupFile>, Box<dyn std::error::Error>> {
+    if mbdb.len() < HEADER_LEN || &mbdb[0..4] != MBDB_MAGIC {
+        return Err("invalid mbdb header".into());
+    }
+    let index = mbdx.map(parse_mbdx).transpose()?.unwrap_or_default();
+
+    let mut reader = MbdbReader {
+        data: mbdb,
+        offset: HEADER_LEN,
+    };
+    let mut files = vec![];
+    while reader.offset < mbdb.len() {
+        let record_offset = reader.offset - HEADER_LEN;
+        let domain = reader.read_string()?;
+        let relative_filename = reader.read_string()?;
+        let _link_target = reader.read_bytes()?;
+        let _data_hash = reader.read_bytes()?;
+        let encryption_key = reader.read_bytes()?;
+        let mode = reader.read_u16()?;
+        let inode = reader.read_u64()?;
+        let user_id = reader.read_u32()?;
+        let group_id = reader.read_u32()?;
+        let last_modified = reader.read_u32()?;
+        let _last_accessed = reader.read_u32()?;
+        let birth = reader.read_u32()?;
+        let size = reader.read_u64()?;
+        let protection_class = ProtectionClass::from(reader.read_u8()? as u32);
+        for _ in 0..reader.read_u8()? {
+            reader.read_bytes()?;
+            reader.read_bytes()?;
+        }
+
+        let fileid = index
+            .get(&record_offset)
+            .cloned()
+            .unwrap_or_else(|| file_id(&domain, &relative_filename));
+        // the key is prefixed by its protection class, same as in Manifest.db
+        let wrapped_encryption_key =
+            (encryption_key.len() > 4).then(|| encryption_key[4..].to_vec());
+
+        files.push(BackupFile {
+            fileid,
+            domain,
+            relative_filename,
+            flags: match mode & 0xF000 {
+                0x4000 => 2,
+                0xA000 => 4,
+                _ => 1,
+            },
+            fileinfo: Some(FileInfo {
+                last_modified: last_modified as u64,
+                last_status_change: last_modified as u64,
+                birth: birth as u64,
+                flags: 0,
+                inode,
+                group_id: group_id as u64,
+                user_id: user_id as u64,
+                size,
+                mode: mode as u64,
+                wrapped_encryption_class: wrapped_encryption_key.as_ref().map(|_| protection_class),
+                protection_class,
+                wrapped_encryption_key,
+                encryption_key: None,
+                extended_attributes: None,
+            }),
+        });
+    }
+    Ok(files)
+}
+
+/// Record offset => file id.
+fn parse_mbdx(mbdx: &[u8]) -> Result<HashMap<usize, String>, Box<dyn std::error::Error>> {
+    if mbdx.len() < HEADER_LEN || &mbdx[0..4] != MBDX_MAGIC {
+        return Err("invalid mbdx header".into());
+    }
+    let mut reader = MbdbReader {
+        data: mbdx,
+        offset: HEADER_LEN,
+    };
+    let count = reader.read_u32()?;
+    let mut index = HashMap::new();
+    for _ in 0..count {
+        let fileid = hex::encode(reader.take(20)?);
+        let offset = reader.read_u32()? as usize;
+        let _mode = reader.read_u16()?;
+        index.insert(offset, fileid);
+    }
+    Ok(index)
+}
+
+/// fileid equals sha1(format!("{}-{}", domain, relative_filename))
+pub fn file_id(domain: &str, relative_filename: &str) -> String {
+    let digest = ring::digest::digest(
+        &ring::digest::SHA1_FOR_LEGACY_USE_ONLY,
+        format!("{}-{}", domain, relative_filename).as_bytes(),
+    );
+    hex::encode(digest.as_ref())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn push_str(data: &mut Vec<u8>, value: Option<&str>) {
+        match value {
+            Some(value) => {
+                data.extend_from_slice(&(value.len() as u16).to_be_bytes());
+                data.extend_from_slice(value.as_bytes());
+            }
+            None => data.extend_from_slice(&[0xFF, 0xFF]),
+        }
+    }
+
+    #[test]
+    fn test_parse_mbdb() {
+        let mut data = b"mbdb\x05\x00".to_vec();
+        push_str(&mut data, Some("HomeDomain"));
+        push_str(&mut data, Some("Library/SMS/sms.db"));
+        push_str(&mut data, None);
+        push_str(&mut data, None);
+        push_str(&mut data, None);
+        data.extend_from_slice(&0x81A4u16.to_be_bytes());
+        data.extend_from_slice(&[0; 8 + 4 + 4 + 4 * 3]);
+        data.extend_from_slice(&1024u64.to_be_bytes());
+        data.extend_from_slice(&[4, 1]);
+        push_str(&mut data, Some("name"));
+        push_str(&mut data, Some("value"));
+
+        let files = parse_mbdb(&data, None).unwrap();
+        assert_eq!(files.len(), 1);
+        assert_eq!(files[0].relative_filename, "Library/SMS/sms.db");
+        assert_eq!(files[0].fileid, "3d0d7e5fb2ce288813306e4d4636395e047a3d28");
+        assert_eq!(files[0].fileinfo.as_ref().unwrap().size, 1024);
+        assert!(files[0]
+            .fileinfo
+            .as_ref()
+            .unwrap()
+            .wrapped_encryption_key
+            .is_none());
+    }
+}
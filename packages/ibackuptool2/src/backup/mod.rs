@@ -1,21 +1,81 @@
 mod file;
 mod info;
 mod manifest;
+mod mbdb;
 mod status;
+mod storage;
+mod tree;
 
 use super::*;
 pub use file::{BackupFile, FileInfo};
 pub use info::BackupInfo;
 pub use manifest::{BackupManifest, BackupManifestLockdown};
+use mbdb::parse_mbdb;
 pub use status::BackupStatus;
+use storage::BackupStorage;
+pub use tree::FileTree;
 
 use std::cmp::min;
 use std::convert::TryFrom;
 use std::fs::{read, write};
+use std::io::Write;
 use std::path::{Path, PathBuf};
 
 use rusqlite::{Connection, OpenFlags};
 
+/// File lookup shared by itunes backups and extracted file trees.
+///
+/// Readers are shared between threads, files may be read concurrently.
+pub trait BackupReader: Send + Sync {
+    /// All files known to this backup.
+    fn files(&self) -> &[BackupFile];
+
+    /// Read the (decrypted) contents of a file.
+    fn read_file(&self, file: &BackupFile) -> Result<Vec<u8>, Box<dyn std::error::Error>>;
+
+    /// Files about to be read, compressed archives extract them in one pass.
+    fn prefetch(&self, _files: &[BackupFile]) -> Result<(), Box<dyn std::error::Error>> {
+        Ok(())
+    }
+
+    fn find_fileid(&self, fileid: &str) -> Option<BackupFile> {
+        self.files()
+            .iter()
+            .find(|file| file.fileid == fileid)
+            .cloned()
+    }
+
+    fn find_path(&self, domain: &str, path: &str) -> Option<BackupFile> {
+        self.files()
+            .iter()
+            .find(|file| file.domain == domain && file.relative_filename == path)
+            .cloned()
+    }
+
+    fn find_wildcard_paths(&self, domain: &str, path: &str) -> Vec<BackupFile> {
+        use wildmatch::WildMatch;
+        let matcher = WildMatch::new(path);
+        self.files()
+            .iter()
+            .filter(|file| file.domain == domain && matcher.is_match(&file.relative_filename))
+            .cloned()
+            .collect()
+    }
+
+    fn find_regex_paths(&self, domain: &str, path: &str) -> Vec<BackupFile> {
+        use regex::Regex;
+        if let Ok(matcher) = Regex::new(path) {
+            self.files()
+                .iter()
+                .filter(|file| file.domain == domain && matcher.is_match(&file.relative_filename))
+                .cloned()
+                .collect()
+        } else {
+            vec![]
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Backup {
     pub path: PathBuf,
@@ -23,22 +83,28 @@ pub struct Backup {
     pub info: BackupInfo,
     pub status: BackupStatus,
     pub files: Vec<BackupFile>,
+    storage: BackupStorage,
+    /// Legacy (iOS 4-9) backups list files in `Manifest.mbdb` and store them
+    /// in the backup root instead of `xx/fileid` folders.
+    legacy: bool,
 }
 
 impl Backup {
-    /// Create from root backup path.
+    /// Create from root backup path, or a zip/tar archive of it.
     pub fn new<P: AsRef<Path>>(path: P) -> Result<Backup, Box<dyn std::error::Error>> {
-        use ::plist::from_file;
-        let status: BackupStatus =
-            from_file(format!("{}/Status.plist", path.as_ref().to_str().unwrap()))?;
+        use ::plist::from_bytes;
+        let storage = BackupStorage::open(&path)?;
 
-        let info: BackupInfo =
-            from_file(format!("{}/Info.plist", path.as_ref().to_str().unwrap()))?;
+        let status: BackupStatus = from_bytes(&storage.read("Status.plist")?)?;
 
-        let manifest: BackupManifest = from_file(format!(
-            "{}/Manifest.plist",
-            path.as_ref().to_str().unwrap()
-        ))?;
+        let info: BackupInfo = from_bytes(&storage.read("Info.plist")?)?;
+
+        let manifest: BackupManifest = from_bytes(&storage.read("Manifest.plist")?)?;
+
+        let legacy = !storage.exists("Manifest.db") && storage.exists("Manifest.mbdb");
+        if legacy {
+            info!("legacy backup found: {}", path.as_ref().display());
+        }
 
         Ok(Backup {
             path: path.as_ref().to_path_buf(),
@@ -46,9 +112,31 @@ impl Backup {
             status,
             info,
             files: vec![],
+            storage,
+            legacy,
         })
     }
 
+    /// Whether path is a backup folder or an archive of one.
+    pub fn is_backup<P: AsRef<Path>>(path: P) -> bool {
+        path.as_ref().join("Manifest.plist").is_file() || Self::is_backup_archive(path)
+    }
+
+    /// Whether path is an archive holding a backup, its listing is searched
+    /// for the manifest database.
+    pub fn is_backup_archive<P: AsRef<Path>>(path: P) -> bool {
+        BackupStorage::is_archive(&path) && BackupStorage::has_manifest(path)
+    }
+
+    /// Where a file is stored, relative to the backup root.
+    fn storage_path(&self, file: &BackupFile) -> String {
+        if self.legacy {
+            file.fileid.clone()
+        } else {
+            format!("{}/{}", &file.fileid[0..2], file.fileid)
+        }
+    }
+
     /// Parse the keybag contained in the manifest.
     pub fn parse_keybag(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         if let Some(bag) = &self.manifest.backup_key_bag {
@@ -65,97 +153,6 @@ impl Backup {
         }
     }
 
-    #[allow(dead_code)]
-    pub fn find_fileid(&self, fileid: &str) -> Option<BackupFile> {
-        for file in &self.files {
-            if file.fileid == fileid {
-                return Some(file.clone());
-            }
-        }
-
-        return None;
-    }
-
-    #[allow(dead_code)]
-    pub fn find_path(&self, domain: &str, path: &str) -> Option<BackupFile> {
-        for file in &self.files {
-            if file.domain == domain && file.relative_filename == path {
-                return Some(file.clone());
-            }
-        }
-
-        return None;
-    }
-
-    pub fn find_wildcard_paths(&self, domain: &str, path: &str) -> Vec<BackupFile> {
-        use wildmatch::WildMatch;
-        let matcher = WildMatch::new(path);
-        let mut paths = vec![];
-        for file in &self.files {
-            if file.domain == domain && matcher.is_match(&file.relative_filename) {
-                paths.push(file.clone());
-            }
-        }
-        return paths;
-    }
-
-    pub fn find_regex_paths(&self, domain: &str, path: &str) -> Vec<BackupFile> {
-        use regex::Regex;
-        if let Ok(matcher) = Regex::new(path) {
-            let mut paths = vec![];
-            for file in &self.files {
-                if file.domain == domain && matcher.is_match(&file.relative_filename) {
-                    paths.push(file.clone());
-                }
-            }
-            paths
-        } else {
-            vec![]
-        }
-    }
-
-    #[allow(dead_code)]
-    pub fn read_file(&self, file: &BackupFile) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
-        let path = format!(
-            "{}/{}/{}",
-            self.path.to_str().expect("path to be str"),
-            (&file.fileid)[0..2].to_string(),
-            file.fileid
-        );
-        let finpath = self.path.join(Path::new(&path));
-
-        debug!("read file path: {}", finpath.display());
-
-        if !finpath.is_file() {
-            return Err(BackupError::InManifestButNotFound.into());
-        }
-
-        let contents = read(&finpath).expect("contents to exist");
-
-        // if the file
-        if self.manifest.is_encrypted {
-            debug!("file {} is encrypted, decrypting...", finpath.display());
-            match &file.fileinfo.as_ref() {
-                Some(fileinfo) => match fileinfo.encryption_key.as_ref() {
-                    Some(encryption_key) => {
-                        let dec = decrypt_with_key(&encryption_key, &contents);
-                        let sliced_dec = dec[..min(fileinfo.size as usize, dec.len())].to_vec();
-                        debug!("file {} is now decrypted...", finpath.display());
-                        return Ok(sliced_dec);
-                    }
-                    None => {
-                        return Err(BackupError::NoEncryptionKey.into());
-                    }
-                },
-                None => {
-                    return Err(BackupError::NoFileInfo.into());
-                }
-            }
-        }
-
-        read(Path::new(&path)).map_err(|e| e.into())
-    }
-
     /// Unwrap all individual file encryption keys
     pub fn unwrap_file_keys(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         let keybag = match &self.manifest.keybag {
@@ -179,10 +176,17 @@ impl Backup {
     pub fn parse_manifest(&mut self) -> Result<(), Box<dyn std::error::Error>> {
         self.files.clear();
 
+        if self.legacy {
+            let mbdx = self.storage.read("Manifest.mbdx").ok();
+            self.files = parse_mbdb(&self.storage.read("Manifest.mbdb")?, mbdx.as_deref())?;
+            return Ok(());
+        }
+
         let conn: Connection;
+        // sqlite needs a real file, keep the extracted copy alive while reading
+        let mut _extracted = None;
         if self.manifest.is_encrypted {
-            let path = format!("{}/Manifest.db", self.path.to_str().unwrap());
-            let contents = read(Path::new(&path)).unwrap();
+            let contents = self.storage.read("Manifest.db")?;
             let dec = decrypt_with_key(
                 &self.manifest.manifest_key_unwrapped.as_ref().unwrap(),
                 &contents,
@@ -202,11 +206,16 @@ impl Backup {
             write(&decpath, dec).unwrap();
 
             conn = Connection::open_with_flags(&decpath, OpenFlags::SQLITE_OPEN_READ_ONLY)?;
-        } else {
+        } else if let BackupStorage::Dir(path) = &self.storage {
             conn = Connection::open_with_flags(
-                format!("{}/Manifest.db", self.path.to_str().unwrap()),
+                path.join("Manifest.db"),
                 OpenFlags::SQLITE_OPEN_READ_ONLY,
             )?;
+        } else {
+            let mut tmpfile = tempfile::NamedTempFile::new()?;
+            tmpfile.write_all(&self.storage.read("Manifest.db")?)?;
+            conn = Connection::open_with_flags(tmpfile.path(), OpenFlags::SQLITE_OPEN_READ_ONLY)?;
+            _extracted = Some(tmpfile);
         }
 
         let mut stmt =
@@ -250,3 +259,49 @@ impl Backup {
         Ok(())
     }
 }
+
+impl BackupReader for Backup {
+    fn files(&self) -> &[BackupFile] {
+        &self.files
+    }
+
+    fn read_file(&self, file: &BackupFile) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let path = self.storage_path(file);
+
+        debug!("read file path: {}", path);
+
+        if !self.storage.exists(&path) {
+            return Err(BackupError::InManifestButNotFound.into());
+        }
+
+        let contents = self.storage.read(&path)?;
+
+        // if the file
+        if self.manifest.is_encrypted {
+            debug!("file {} is encrypted, decrypting...", path);
+            match &file.fileinfo.as_ref() {
+                Some(fileinfo) => match fileinfo.encryption_key.as_ref() {
+                    Some(encryption_key) => {
+                        let dec = decrypt_with_key(encryption_key, &contents);
+                        let sliced_dec = dec[..min(fileinfo.size as usize, dec.len())].to_vec();
+                        debug!("file {} is now decrypted...", path);
+                        return Ok(sliced_dec);
+                    }
+                    None => {
+                        return Err(BackupError::NoEncryptionKey.into());
+                    }
+                },
+                None => {
+                    return Err(BackupError::NoFileInfo.into());
+                }
+            }
+        }
+
+        Ok(contents)
+    }
+
+    fn prefetch(&self, files: &[BackupFile]) -> Result<(), Box<dyn std::error::Error>> {
+        let paths: Vec<_> = files.iter().map(|file| self.storage_path(file)).collect();
+        Ok(self.storage.extract(&paths)?)
+    }
+}
@@ -0,0 +1,420 @@
+use super::*;
+use std::collections::{HashMap, HashSet};
+use std::fs::File;
+use std::io::{Error, ErrorKind, Read, Seek, SeekFrom};
+use std::sync::Mutex;
+use tempfile::TempDir;
+use zip::ZipArchive;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum TarCompression {
+    None,
+    Gzip,
+    Zstd,
+}
+
+impl TarCompression {
+    fn detect(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_lowercase();
+        if name.ends_with(".tar") {
+            Some(Self::None)
+        } else if name.ends_with(".tar.gz") || name.ends_with(".tgz") {
+            Some(Self::Gzip)
+        } else if name.ends_with(".tar.zst") || name.ends_with(".tzst") {
+            Some(Self::Zstd)
+        } else {
+            None
+        }
+    }
+
+    fn open(&self, path: &Path) -> std::io::Result<Box<dyn Read + Send>> {
+        let file = File::open(path)?;
+        Ok(match self {
+            Self::None => Box::new(file),
+            Self::Gzip => Box::new(flate2::read::GzDecoder::new(file)),
+            Self::Zstd => Box::new(zstd::stream::read::Decoder::new(file)?),
+        })
+    }
+}
+
+/// Position of a file inside a plain tar.
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct TarEntry {
+    offset: u64,
+    size: u64,
+}
+
+/// Files at the backup root, extracted from compressed tars while listing them.
+const ROOT_FILES: [&str; 6] = [
+    "Manifest.plist",
+    "Manifest.db",
+    "Manifest.mbdb",
+    "Manifest.mbdx",
+    "Info.plist",
+    "Status.plist",
+];
+
+/// Where the files of a backup are stored: a plain folder, or an archive
+/// of that folder. Zips and plain tars are read in place, compressed tars
+/// can't seek, their files are extracted to a temporary folder by streaming
+/// the archive again for the files requested.
+pub(crate) enum BackupStorage {
+    Dir(PathBuf),
+    Zip {
+        archive: Mutex<ZipArchive<File>>,
+        prefix: String,
+    },
+    Tar {
+        path: PathBuf,
+        prefix: String,
+        entries: HashMap<String, TarEntry>,
+    },
+    Compressed {
+        path: PathBuf,
+        compression: TarCompression,
+        prefix: String,
+        /// every file in the archive, prefix included
+        names: HashSet<String>,
+        /// extracted files keep their name in the archive
+        dir: TempDir,
+        extracted: Mutex<HashSet<String>>,
+    },
+}
+
+impl std::fmt::Debug for BackupStorage {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Dir(path) => write!(f, "Dir({})", path.display()),
+            Self::Zip { prefix, .. } => write!(f, "Zip({})", prefix),
+            Self::Tar { path, .. } => write!(f, "Tar({})", path.display()),
+            Self::Compressed { path, dir, .. } => {
+                write!(
+                    f,
+                    "Compressed({}, {})",
+                    path.display(),
+                    dir.path().display()
+                )
+            }
+        }
+    }
+}
+
+impl BackupStorage {
+    /// Whether the path looks like an archive this storage can read.
+    pub fn is_archive<P: AsRef<Path>>(path: P) -> bool {
+        let path = path.as_ref();
+        path.is_file()
+            && (TarCompression::detect(path).is_some()
+                || path
+                    .extension()
+                    .is_some_and(|ext| ext.eq_ignore_ascii_case("zip")))
+    }
+
+    /// Whether an archive lists `Manifest.db`, or `Manifest.mbdb` of legacy backups.
+    /// Compressed tars are read until it is found, only their headers are parsed.
+    pub fn has_manifest<P: AsRef<Path>>(path: P) -> bool {
+        let is_manifest = |name: &str| {
+            matches!(
+                name.rsplit('/').next(),
+                Some("Manifest.db") | Some("Manifest.mbdb")
+            )
+        };
+        let path = path.as_ref();
+        let found = match TarCompression::detect(path) {
+            Some(compression) => compression.open(path).and_then(|reader| {
+                let mut archive = tar::Archive::new(reader);
+                for entry in archive.entries()? {
+                    if is_manifest(&String::from_utf8_lossy(&entry?.path_bytes())) {
+                        return Ok(true);
+                    }
+                }
+                Ok(false)
+            }),
+            None => File::open(path).and_then(|file| {
+                Ok(ZipArchive::new(file)
+                    .map_err(|e| Error::new(ErrorKind::InvalidData, e))?
+                    .file_names()
+                    .any(is_manifest))
+            }),
+        };
+        found.unwrap_or_else(|e| {
+            debug!("cannot list archive: {}, {}", path.display(), e);
+            false
+        })
+    }
+
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Box<dyn std::error::Error>> {
+        let path = path.as_ref();
+        if path.is_dir() {
+            Ok(Self::Dir(path.to_path_buf()))
+        } else if let Some(compression) = TarCompression::detect(path) {
+            Self::open_tar(path, compression)
+        } else {
+            let archive = ZipArchive::new(File::open(path)?)?;
+            let prefix = Self::find_prefix(archive.file_names())?;
+            Ok(Self::Zip {
+                archive: Mutex::new(archive),
+                prefix,
+            })
+        }
+    }
+
+    /// Archives usually wrap the backup in a folder named by the device udid.
+    fn find_prefix<'a, I: Iterator<Item = &'a str>>(
+        names: I,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        names
+            .filter(|name| *name == "Manifest.plist" || name.ends_with("/Manifest.plist"))
+            .min_by_key(|name| name.len())
+            .map(|name| name.trim_end_matches("Manifest.plist").to_string())
+            .ok_or_else(|| BackupError::FileNotFound.into())
+    }
+
+    fn open_tar(
+        path: &Path,
+        compression: TarCompression,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        if compression != TarCompression::None {
+            return Self::list_tar(path, compression);
+        }
+        info!("indexing tar archive: {}", path.display());
+        let mut archive = tar::Archive::new(File::open(path)?);
+        let mut entries = HashMap::new();
+        // headers only, the data in between is skipped by seeking
+        for entry in archive.entries_with_seek()? {
+            let entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            entries.insert(
+                Self::entry_name(&entry),
+                TarEntry {
+                    offset: entry.raw_file_position(),
+                    size: entry.size(),
+                },
+            );
+        }
+        let prefix = Self::find_prefix(entries.keys().map(|k| k.as_str()))?;
+        info!("indexed {} files in {}", entries.len(), path.display());
+        Ok(Self::Tar {
+            path: path.to_path_buf(),
+            prefix,
+            entries,
+        })
+    }
+
+    /// Lists a compressed tar in one streaming pass, extracting only the
+    /// manifest and the other files at the backup root.
+    fn list_tar(
+        path: &Path,
+        compression: TarCompression,
+    ) -> Result<Self, Box<dyn std::error::Error>> {
+        let dir = tempfile::tempdir()?;
+        info!("listing tar archive: {}", path.display());
+        let mut archive = tar::Archive::new(compression.open(path)?);
+        let mut names = HashSet::new();
+        let mut root_files = vec![];
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = Self::entry_name(&entry);
+            let is_root_file = name
+                .rsplit('/')
+                .next()
+                .is_some_and(|file| ROOT_FILES.contains(&file));
+            // entries escaping the folder are skipped
+            if is_root_file && entry.unpack_in(dir.path())? {
+                root_files.push(name.clone());
+            }
+            names.insert(name);
+        }
+        let prefix = Self::find_prefix(names.iter().map(|n| n.as_str()))?;
+        info!("listed {} files in {}", names.len(), path.display());
+        Ok(Self::Compressed {
+            path: path.to_path_buf(),
+            compression,
+            prefix,
+            names,
+            dir,
+            extracted: Mutex::new(root_files.into_iter().collect()),
+        })
+    }
+
+    /// Extract files of a compressed tar before reading them, in one streaming
+    /// pass over the archive. Names are relative to the backup root, other
+    /// storages read their files in place and ignore this.
+    pub fn extract<S: AsRef<str>>(&self, files: &[S]) -> std::io::Result<()> {
+        let (path, compression, prefix, names, dir, extracted) = match self {
+            Self::Compressed {
+                path,
+                compression,
+                prefix,
+                names,
+                dir,
+                extracted,
+            } => (path, compression, prefix, names, dir, extracted),
+            _ => return Ok(()),
+        };
+        // one pass at a time, readers wait for the files being extracted
+        let mut extracted = extracted.lock().map_err(|e| Error::other(e.to_string()))?;
+        let mut wanted: HashSet<String> = files
+            .iter()
+            .map(|name| format!("{}{}", prefix, name.as_ref()))
+            .filter(|name| names.contains(name) && !extracted.contains(name))
+            .collect();
+        if wanted.is_empty() {
+            return Ok(());
+        }
+        info!("extracting {} files from {}", wanted.len(), path.display());
+        let mut archive = tar::Archive::new(compression.open(path)?);
+        for entry in archive.entries()? {
+            let mut entry = entry?;
+            if !entry.header().entry_type().is_file() {
+                continue;
+            }
+            let name = Self::entry_name(&entry);
+            if wanted.remove(&name) {
+                if entry.unpack_in(dir.path())? {
+                    extracted.insert(name);
+                }
+                if wanted.is_empty() {
+                    break;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn entry_name<R: Read>(entry: &tar::Entry<'_, R>) -> String {
+        String::from_utf8_lossy(&entry.path_bytes())
+            .trim_start_matches("./")
+            .to_string()
+    }
+
+    /// Whether a file exists, name is relative to the backup root.
+    pub fn exists(&self, name: &str) -> bool {
+        match self {
+            Self::Dir(root) => root.join(name).is_file(),
+            Self::Zip { archive, prefix } => archive
+                .lock()
+                .map(|mut archive| archive.by_name(&format!("{}{}", prefix, name)).is_ok())
+                .unwrap_or(false),
+            Self::Tar {
+                prefix, entries, ..
+            } => entries.contains_key(&format!("{}{}", prefix, name)),
+            Self::Compressed { prefix, names, .. } => {
+                names.contains(&format!("{}{}", prefix, name))
+            }
+        }
+    }
+
+    /// Read a whole file, name is relative to the backup root.
+    pub fn read(&self, name: &str) -> std::io::Result<Vec<u8>> {
+        match self {
+            Self::Dir(root) => read(root.join(name)),
+            Self::Zip { archive, prefix } => {
+                let mut archive = archive.lock().map_err(|e| Error::other(e.to_string()))?;
+                let mut file = archive
+                    .by_name(&format!("{}{}", prefix, name))
+                    .map_err(|e| Error::new(ErrorKind::NotFound, e))?;
+                let mut data = Vec::with_capacity(file.size() as usize);
+                file.read_to_end(&mut data)?;
+                Ok(data)
+            }
+            Self::Tar {
+                path,
+                prefix,
+                entries,
+            } => {
+                let name = format!("{}{}", prefix, name);
+                let entry = entries
+                    .get(&name)
+                    .ok_or_else(|| Error::new(ErrorKind::NotFound, name.clone()))?;
+                let mut data = vec![0; entry.size as usize];
+                let mut file = File::open(path)?;
+                file.seek(SeekFrom::Start(entry.offset))?;
+                file.read_exact(&mut data)?;
+                Ok(data)
+            }
+            Self::Compressed { prefix, dir, .. } => {
+                // files not requested ahead take a pass of their own
+                self.extract(&[name])?;
+                read(dir.path().join(format!("{}{}", prefix, name)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_tar_storage() {
+        let dir = tempfile::tempdir().unwrap();
+        for name in ["backup.tar", "backup.tar.gz"] {
+            let path = dir.path().join(name);
+            let file = File::create(&path).unwrap();
+            let writer: Box<dyn std::io::Write> = if name.ends_with(".gz") {
+                Box::new(flate2::write::GzEncoder::new(
+                    file,
+                    flate2::Compression::default(),
+                ))
+            } else {
+                Box::new(file)
+            };
+            let mut builder = tar::Builder::new(writer);
+            for (name, data) in [
+                ("udid/Manifest.plist", &b"manifest"[..]),
+                ("udid/Manifest.db", b"database"),
+                ("udid/ab/abcd", b"first"),
+                ("udid/cd/cdef", b"second"),
+            ] {
+                let mut header = tar::Header::new_gnu();
+                header.set_size(data.len() as u64);
+                header.set_mode(0o644);
+                header.set_cksum();
+                builder.append_data(&mut header, name, data).unwrap();
+            }
+            builder.into_inner().unwrap().flush().unwrap();
+
+            assert!(BackupStorage::is_archive(&path));
+            assert!(BackupStorage::has_manifest(&path));
+            let storage = BackupStorage::open(&path).unwrap();
+            assert!(storage.exists("ab/abcd"));
+            assert!(!storage.exists("ab/missing"));
+            assert_eq!(storage.read("Manifest.plist").unwrap(), b"manifest");
+            if let BackupStorage::Compressed { extracted, .. } = &storage {
+                // only the root files on the first pass
+                assert_eq!(extracted.lock().unwrap().len(), 2);
+                storage.extract(&["cd/cdef", "ab/missing"]).unwrap();
+                assert!(extracted.lock().unwrap().contains("udid/cd/cdef"));
+                assert!(!extracted.lock().unwrap().contains("udid/ab/abcd"));
+            }
+            assert_eq!(storage.read("cd/cdef").unwrap(), b"second");
+            assert_eq!(storage.read("ab/abcd").unwrap(), b"first");
+            assert!(storage.read("ab/missing").is_err());
+        }
+    }
+
+    #[test]
+    fn test_archive_without_manifest() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("photos.tar");
+        let mut builder = tar::Builder::new(File::create(&path).unwrap());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(5);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder
+            .append_data(&mut header, "photos/a.jpg", &b"image"[..])
+            .unwrap();
+        builder.finish().unwrap();
+
+        assert!(BackupStorage::is_archive(&path));
+        assert!(!BackupStorage::has_manifest(&path));
+        assert!(!Backup::is_backup(&path));
+    }
+}
@@ -0,0 +1,185 @@
+use super::*;
+use std::collections::HashMap;
+use walkdir::WalkDir;
+
+const CONTAINER_METADATA: &str = ".com.apple.mobile_container_manager.metadata.plist";
+
+/// An extracted app container or filesystem dump, e.g. from forensic tools
+/// or jailbroken devices, exposed with the same lookup api as `Backup`.
+///
+/// Files are mapped to backup domains: app containers by their container
+/// metadata, `var/mobile` to `HomeDomain` / `MediaDomain`. The `fileid` of a
+/// file is its path on disk, relative to the tree root.
+#[derive(Debug)]
+pub struct FileTree {
+    pub path: PathBuf,
+    pub files: Vec<BackupFile>,
+}
+
+impl FileTree {
+    /// Create from a filesystem dump or app container, detecting domains.
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<FileTree, Box<dyn std::error::Error>> {
+        let root = path.as_ref().to_path_buf();
+        let paths = Self::walk(&root)?;
+
+        let containers = paths
+            .iter()
+            .filter(|path| path.file_name().is_some_and(|n| n == CONTAINER_METADATA))
+            .filter_map(|path| {
+                let container = path.parent()?.to_path_buf();
+                Self::container_domain(&root.join(path), &container)
+                    .map_err(|e| warn!("failed to read container metadata: {}", e))
+                    .ok()
+                    .map(|domain| (container, domain))
+            })
+            .collect::<HashMap<_, _>>();
+
+        let files = paths
+            .iter()
+            .filter_map(|path| {
+                path.ancestors()
+                    .skip(1)
+                    .find_map(|ancestor| {
+                        let relative = path.strip_prefix(ancestor).ok()?.to_str()?;
+                        if let Some(domain) = containers.get(ancestor) {
+                            Some((domain.clone(), relative.to_string()))
+                        } else if ancestor.ends_with("var/mobile") {
+                            let domain = if relative.starts_with("Media/") {
+                                "MediaDomain"
+                            } else {
+                                "HomeDomain"
+                            };
+                            Some((domain.to_string(), relative.to_string()))
+                        } else {
+                            None
+                        }
+                    })
+                    .map(|(domain, relative_filename)| {
+                        Self::backup_file(path, domain, relative_filename)
+                    })
+            })
+            .collect::<Vec<_>>();
+
+        info!(
+            "loaded {} files from {} containers in {}",
+            files.len(),
+            containers.len(),
+            root.display()
+        );
+        Ok(FileTree { path: root, files })
+    }
+
+    /// Create from a single app container whose domain can't be detected,
+    /// every file is placed in `domain`.
+    pub fn with_domain<P: AsRef<Path>>(
+        path: P,
+        domain: &str,
+    ) -> Result<FileTree, Box<dyn std::error::Error>> {
+        let root = path.as_ref().to_path_buf();
+        let files = Self::walk(&root)?
+            .iter()
+            .filter_map(|path| {
+                path.to_str()
+                    .map(|relative| Self::backup_file(path, domain.into(), relative.into()))
+            })
+            .collect();
+        Ok(FileTree { path: root, files })
+    }
+
+    /// Relative paths of every regular file under root.
+    fn walk(root: &Path) -> Result<Vec<PathBuf>, Box<dyn std::error::Error>> {
+        if !root.is_dir() {
+            return Err(BackupError::FileNotFound.into());
+        }
+        Ok(WalkDir::new(root)
+            .into_iter()
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.file_type().is_file())
+            .filter_map(|entry| {
+                entry
+                    .path()
+                    .strip_prefix(root)
+                    .ok()
+                    .map(|path| path.to_path_buf())
+            })
+            .collect())
+    }
+
+    fn container_domain(
+        metadata: &Path,
+        container: &Path,
+    ) -> Result<String, Box<dyn std::error::Error>> {
+        let value = ::plist::Value::from_file(metadata)?;
+        let identifier = value
+            .as_dictionary()
+            .and_then(|dict| dict.get("MCMMetadataIdentifier"))
+            .and_then(|id| id.as_string())
+            .ok_or(BackupError::NoFileInfo)?;
+        let is_group = container
+            .components()
+            .any(|component| component.as_os_str() == "AppGroup");
+        Ok(if is_group {
+            format!("AppDomainGroup-{}", identifier)
+        } else {
+            format!("AppDomain-{}", identifier)
+        })
+    }
+
+    fn backup_file(path: &Path, domain: String, relative_filename: String) -> BackupFile {
+        BackupFile {
+            fileid: path.to_string_lossy().into(),
+            domain,
+            relative_filename,
+            flags: 1,
+            fileinfo: None,
+        }
+    }
+}
+
+impl BackupReader for FileTree {
+    fn files(&self) -> &[BackupFile] {
+        &self.files
+    }
+
+    fn read_file(&self, file: &BackupFile) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+        let path = self.path.join(&file.fileid);
+        debug!("read file path: {}", path.display());
+        if !path.is_file() {
+            return Err(BackupError::InManifestButNotFound.into());
+        }
+        read(path).map_err(|e| e.into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs::{create_dir_all, write};
+
+    #[test]
+    fn test_tree_domains() {
+        let root = tempfile::tempdir().unwrap();
+        let mobile = root.path().join("private/var/mobile");
+        let container = mobile.join("Containers/Data/Application/UUID");
+        create_dir_all(mobile.join("Library/SMS")).unwrap();
+        create_dir_all(container.join("Documents")).unwrap();
+        write(mobile.join("Library/SMS/sms.db"), b"sms").unwrap();
+        write(container.join("Documents/MM.sqlite"), b"mm").unwrap();
+        let mut dict = ::plist::Dictionary::new();
+        dict.insert(
+            "MCMMetadataIdentifier".into(),
+            ::plist::Value::String("com.tencent.xin".into()),
+        );
+        ::plist::Value::Dictionary(dict)
+            .to_file_xml(container.join(CONTAINER_METADATA))
+            .unwrap();
+
+        let tree = FileTree::new(root.path()).unwrap();
+        let sms = tree.find_path("HomeDomain", "Library/SMS/sms.db").unwrap();
+        assert_eq!(tree.read_file(&sms).unwrap(), b"sms");
+        let mm = tree
+            .find_path("AppDomain-com.tencent.xin", "Documents/MM.sqlite")
+            .unwrap();
+        assert_eq!(tree.read_file(&mm).unwrap(), b"mm");
+    }
+}